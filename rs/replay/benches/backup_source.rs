@@ -0,0 +1,63 @@
+//! Measures reading the artifacts of a backup spool with different read
+//! buffer sizes. The spool is created in a temporary directory; set
+//! `BACKUP_SPOOL_BENCH_DIR` to a directory on another file system, e.g. an NFS
+//! mount, to measure the effect there.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ic_config::artifact_pool::BACKUP_GROUP_SIZE;
+use ic_replay::player::{BackupSource, FsBackupSource};
+use ic_types::Height;
+use std::path::Path;
+
+const HEIGHTS: u64 = 100;
+const ARTIFACT_SIZE: usize = 1 << 20;
+const ARTIFACT_NAME: &str = "block_proposal.bin";
+
+// Writes one artifact of `ARTIFACT_SIZE` bytes for each height to the spool.
+fn create_spool(dir: &Path) {
+    for height in 1..=HEIGHTS {
+        let group_key = (height / BACKUP_GROUP_SIZE) * BACKUP_GROUP_SIZE;
+        let height_dir = dir.join(group_key.to_string()).join(height.to_string());
+        std::fs::create_dir_all(&height_dir).unwrap();
+        std::fs::write(
+            height_dir.join(ARTIFACT_NAME),
+            vec![height as u8; ARTIFACT_SIZE],
+        )
+        .unwrap();
+    }
+}
+
+fn read_artifacts(c: &mut Criterion) {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("backup_spool_bench");
+    let tmp = match std::env::var_os("BACKUP_SPOOL_BENCH_DIR") {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir(),
+    }
+    .unwrap();
+    create_spool(tmp.path());
+
+    let mut group = c.benchmark_group("read_artifact");
+    group.throughput(Throughput::Bytes(HEIGHTS * ARTIFACT_SIZE as u64));
+    for read_buffer_size in [None, Some(64 << 10), Some(1 << 20)] {
+        let source =
+            FsBackupSource::new(tmp.path().to_path_buf()).with_read_buffer_size(read_buffer_size);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", read_buffer_size)),
+            &source,
+            |b, source| {
+                b.iter(|| {
+                    for height in 1..=HEIGHTS {
+                        source
+                            .read_artifact(Height::from(height), ARTIFACT_NAME)
+                            .unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read_artifacts);
+criterion_main!(benches);
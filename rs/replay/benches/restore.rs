@@ -0,0 +1,135 @@
+//! Compares restoring a backup spool with and without overlapping the artifact
+//! deserialization with the state computation. A restore needs a consistent
+//! set of a replica config, a checkpoint, a backup spool with signed CUPs and a
+//! registry local store, which can't be generated synthetically, so the bench
+//! runs against a fixture passed through the environment and is skipped
+//! unless all of the following variables are set:
+//!
+//! * `RESTORE_BENCH_CONFIG`: the replica config file.
+//! * `RESTORE_BENCH_STATE`: the state directory containing the checkpoint at
+//!   the start height. It is copied for every iteration and stays untouched.
+//! * `RESTORE_BENCH_SPOOL`: the backup spool.
+//! * `RESTORE_BENCH_REGISTRY`: the registry local store.
+//! * `RESTORE_BENCH_SUBNET_ID`, `RESTORE_BENCH_REPLICA_VERSION` and
+//!   `RESTORE_BENCH_START_HEIGHT`: the subnet, the replica version and the
+//!   height of the CUP to restore from.
+//!
+//! A fixture can be taken from the backup of any subnet: copy the spool, the
+//! registry local store and the state directory with the checkpoint at a CUP
+//! height, and set the start height to this CUP height. A spool covering a few
+//! DKG intervals is enough to see the difference, e.g.
+//!
+//! ```text
+//! RESTORE_BENCH_CONFIG=ic.json5 RESTORE_BENCH_STATE=state \
+//!     RESTORE_BENCH_SPOOL=spool RESTORE_BENCH_REGISTRY=ic_registry_local_store \
+//!     RESTORE_BENCH_SUBNET_ID=<subnet id> RESTORE_BENCH_REPLICA_VERSION=<version> \
+//!     RESTORE_BENCH_START_HEIGHT=<height> cargo bench --bench restore
+//! ```
+//!
+//! Criterion reports the time of `restore/pipelined/false` (the sequential
+//! restore) and `restore/pipelined/true` side by side.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ic_config::{state_manager::Config as StateManagerConfig, Config, ConfigSource};
+use ic_replay::player::Player;
+use ic_types::{PrincipalId, ReplicaVersion, SubnetId};
+use std::{convert::TryFrom, path::PathBuf, str::FromStr};
+
+// The parameters of the restore, read from the environment.
+struct RestoreParams {
+    config: PathBuf,
+    state: PathBuf,
+    spool: PathBuf,
+    registry: PathBuf,
+    subnet_id: SubnetId,
+    replica_version: ReplicaVersion,
+    start_height: u64,
+}
+
+impl RestoreParams {
+    fn from_env() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).ok();
+        Some(Self {
+            config: var("RESTORE_BENCH_CONFIG")?.into(),
+            state: var("RESTORE_BENCH_STATE")?.into(),
+            spool: var("RESTORE_BENCH_SPOOL")?.into(),
+            registry: var("RESTORE_BENCH_REGISTRY")?.into(),
+            subnet_id: SubnetId::from(
+                PrincipalId::from_str(&var("RESTORE_BENCH_SUBNET_ID")?)
+                    .expect("Couldn't parse the subnet id"),
+            ),
+            replica_version: ReplicaVersion::try_from(
+                var("RESTORE_BENCH_REPLICA_VERSION")?.as_str(),
+            )
+            .expect("Couldn't parse the replica version"),
+            start_height: var("RESTORE_BENCH_START_HEIGHT")?
+                .parse()
+                .expect("Couldn't parse the start height"),
+        })
+    }
+}
+
+fn restore(c: &mut Criterion) {
+    let params = match RestoreParams::from_env() {
+        Some(params) => params,
+        None => {
+            eprintln!(
+                "Skipping the restore bench, because not all RESTORE_BENCH_* variables are set, see the documentation of benches/restore.rs"
+            );
+            return;
+        }
+    };
+    let rt = tokio::runtime::Runtime::new().expect("Could not create tokio runtime.");
+    Config::run_with_temp_config(|default_config| {
+        let mut cfg =
+            Config::load_with_default(&ConfigSource::File(params.config.clone()), default_config)
+                .expect("Failed to load config");
+        cfg.state_manager = StateManagerConfig::new(params.state.clone());
+
+        let mut group = c.benchmark_group("restore");
+        // A single restore takes seconds, so we only take the minimum number of
+        // samples.
+        group.sample_size(10);
+        for pipelined_restore in [false, true] {
+            group.bench_with_input(
+                BenchmarkId::new("pipelined", pipelined_restore),
+                &pipelined_restore,
+                |b, pipelined_restore| {
+                    b.iter_batched(
+                        || {
+                            let scratch = tempfile::tempdir().unwrap();
+                            let cfg = Player::overlay_state_config(
+                                cfg.clone(),
+                                scratch.path().join("state"),
+                                &mut std::io::sink(),
+                            )
+                            .unwrap();
+                            let player = rt
+                                .block_on(Player::new_for_backup(
+                                    cfg,
+                                    params.replica_version.clone(),
+                                    &params.spool,
+                                    &params.registry,
+                                    params.subnet_id,
+                                    params.start_height,
+                                ))
+                                .with_pipelined_restore(*pipelined_restore);
+                            (scratch, player)
+                        },
+                        // The player and the state copy are returned, so that they
+                        // are dropped outside of the measurement.
+                        |(scratch, mut player)| {
+                            player.restore(params.start_height + 1).unwrap();
+                            (player, scratch)
+                        },
+                        BatchSize::PerIteration,
+                    )
+                },
+            );
+        }
+        group.finish();
+    });
+}
+
+criterion_group!(benches, restore);
+criterion_main!(benches);
@@ -7,7 +7,7 @@ use ic_interfaces::{
     consensus_pool::{ChangeAction, MutableConsensusPool},
     crypto::MultiSigVerifier,
     registry::RegistryClient,
-    time_source::SysTimeSource,
+    time_source::TimeSource,
     validation::ValidationResult,
 };
 use ic_protobuf::types::v1 as pb;
@@ -17,39 +17,197 @@ use ic_types::{
         certification::Certification, BlockProposal, CatchUpPackage, Finalization, Notarization,
         RandomBeacon, RandomTape,
     },
-    Height, RegistryVersion, SubnetId,
+    Height, RegistryVersion, ReplicaVersion, SubnetId,
 };
 use prost::Message;
 use std::{
     collections::{BTreeMap, BTreeSet},
     convert::TryFrom,
     fs,
-    io::Read,
+    io::{self, Read, Write},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
+/// A source of the backup artifacts of a single subnet and replica version,
+/// organized by height. Artifacts are identified by their file names in the
+/// backup spool, e.g. `random_beacon.bin`.
+pub trait BackupSource: Send + Sync {
+    /// Returns the names of all artifacts of each height starting from the
+    /// given height.
+    fn list_heights(
+        &self,
+        start_height: Height,
+    ) -> Result<BTreeMap<Height, Vec<String>>, std::io::Error>;
+
+    /// Reads the artifact with the given name at the given height and returns
+    /// its content as bytes.
+    fn read_artifact(&self, height: Height, name: &str) -> Result<Vec<u8>, std::io::Error>;
+
+    /// Reads the protobuf bytes of the CUP at the given height as stored.
+    fn read_cup_bytes(&self, height: Height) -> Result<Vec<u8>, String> {
+        self.read_artifact(height, CUP_FILE_NAME)
+            .map_err(|err| format!("Couldn't read the CUP at height {}: {:?}", height, err))
+    }
+
+    /// Reads the CUP at the given height.
+    fn read_cup(&self, height: Height) -> Result<CatchUpPackage, String> {
+        let buffer = self.read_cup_bytes(height)?;
+        let protobuf = pb::CatchUpPackage::decode(buffer.as_slice())
+            .map_err(|err| format!("Protobuf decoding failed: {:?}", err))?;
+        CatchUpPackage::try_from(&protobuf).map_err(|_| deserialization_error(height))
+    }
+}
+
+/// A backup source reading the artifacts from a backup spool on the local file
+/// system, where the artifacts of each height are stored in the directory
+/// `<backup_dir>/<group>/<height>`.
+pub struct FsBackupSource {
+    backup_dir: PathBuf,
+    read_buffer_size: Option<usize>,
+}
+
+impl FsBackupSource {
+    /// Creates a backup source for the given spool directory of a subnet and
+    /// replica version.
+    pub fn new(backup_dir: PathBuf) -> Self {
+        Self {
+            backup_dir,
+            read_buffer_size: None,
+        }
+    }
+
+    /// Reads the artifacts in chunks of the given size in bytes into a buffer
+    /// preallocated from the file length, so that each read call transfers up
+    /// to that many bytes, e.g. to issue fewer, larger requests on a network
+    /// file system. By default, the whole file is read with `read_to_end`.
+    pub fn with_read_buffer_size(mut self, read_buffer_size: Option<usize>) -> Self {
+        self.read_buffer_size = read_buffer_size;
+        self
+    }
+
+    /// Returns the spool directory of this backup source.
+    pub fn path(&self) -> &Path {
+        &self.backup_dir
+    }
+
+    // Returns the directory containing the artifacts of the given height.
+    fn height_dir(&self, height: Height) -> PathBuf {
+        let group_key = (height.get() / BACKUP_GROUP_SIZE) * BACKUP_GROUP_SIZE;
+        self.backup_dir
+            .join(group_key.to_string())
+            .join(height.to_string())
+    }
+}
+
+impl BackupSource for FsBackupSource {
+    fn list_heights(
+        &self,
+        start_height: Height,
+    ) -> Result<BTreeMap<Height, Vec<String>>, std::io::Error> {
+        let mut results = BTreeMap::new();
+        for group_dir in fs::read_dir(&self.backup_dir)? {
+            for height_dir in fs::read_dir(group_dir?.path())? {
+                let path = height_dir?.path();
+                let height = Height::from(
+                    path.file_name()
+                        .unwrap_or_default()
+                        .to_str()
+                        .unwrap_or_default()
+                        .parse::<u64>()
+                        .expect("Couldn't parse the height directory name"),
+                );
+
+                // Skip all height folders below the start height,
+                if height < start_height {
+                    continue;
+                }
+                let mut files = Vec::new();
+                for file in fs::read_dir(&path)? {
+                    let file_path = file?.path();
+                    files.push(
+                        file_path
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                    );
+                }
+                results.insert(height, files);
+            }
+        }
+        Ok(results)
+    }
+
+    fn read_artifact(&self, height: Height, name: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut buffer = Vec::new();
+        let mut file = fs::File::open(self.height_dir(height).join(name))?;
+        let size = match self.read_buffer_size {
+            Some(size) => size.max(1),
+            None => {
+                file.read_to_end(&mut buffer)?;
+                return Ok(buffer);
+            }
+        };
+        buffer.reserve(file.metadata()?.len() as usize);
+        let mut chunk = vec![0; size];
+        loop {
+            match file.read(&mut chunk) {
+                Ok(0) => return Ok(buffer),
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Kinds of conflicting artifacts found at a single height of the backup spool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Finalizations of several different blocks, given by their hashes.
+    ConflictingFinalizations(Vec<String>),
+    /// Several block proposals with differing contents for the block with the
+    /// given hash.
+    DuplicateProposals(String),
+}
+
+const CUP_FILE_NAME: &str = "catch_up_package.bin";
+const RANDOM_BEACON_FILE_NAME: &str = "random_beacon.bin";
+const RANDOM_TAPE_FILE_NAME: &str = "random_tape.bin";
+
 // A set of backup artifacts corresponding to a single height.
 pub(super) struct HeightArtifacts {
-    path: PathBuf,
     contains_cup: bool,
+    contains_random_beacon: bool,
+    contains_random_tape: bool,
     proposals: Vec<String>,
     finalizations: Vec<String>,
     notarizations: Vec<String>,
 }
 
-// Reads the file at `path` and the returns the content as bytes.
-fn read_file(path: &Path) -> Vec<u8> {
-    let mut buffer = Vec::new();
-    let mut file = fs::File::open(path)
-        .unwrap_or_else(|err| panic!("Couldn't open file {:?}: {:?}", path, err));
-    file.read_to_end(&mut buffer)
-        .unwrap_or_else(|err| panic!("Couldn't read file {:?}: {:?}", path, err));
-    buffer
+impl HeightArtifacts {
+    /// Returns true if the height contains a CUP.
+    pub(crate) fn contains_cup(&self) -> bool {
+        self.contains_cup
+    }
+}
+
+// Reads the artifact with the given name at the given height and returns the
+// content as bytes.
+fn read_artifact(source: &dyn BackupSource, height: Height, name: &str) -> Vec<u8> {
+    source.read_artifact(height, name).unwrap_or_else(|err| {
+        panic!(
+            "Couldn't read the artifact {} at height {}: {:?}",
+            name, height, err
+        )
+    })
 }
 
 /// All possible exits from the deserialization loop of the artifacts. All
 /// exits except for `Done` require for the upper layers to catch up.
+#[derive(Debug)]
 pub(crate) enum ExitPoint {
     /// All available complete rounds were successfully restored.
     Done,
@@ -71,88 +229,246 @@ pub(crate) enum ExitPoint {
 /// Deserialize the CUP at the given height and inserts it into the pool.
 pub(crate) fn insert_cup_at_height(
     pool: &mut dyn MutableConsensusPool,
-    backup_dir: &Path,
+    time_source: &dyn TimeSource,
+    source: &dyn BackupSource,
     height: Height,
 ) {
-    let cup = read_cup_at_height(backup_dir, height);
+    let cup = read_cup_at_height(source, height);
     pool.apply_changes(
-        &SysTimeSource::new(),
+        time_source,
         ChangeAction::AddToValidated(cup.into_message()).into(),
     );
 }
 
 /// Deserializes the CUP at the given height and returns it.
-pub(crate) fn read_cup_at_height(backup_dir: &Path, height: Height) -> CatchUpPackage {
-    let group_key = (height.get() / BACKUP_GROUP_SIZE) * BACKUP_GROUP_SIZE;
-    let buffer = read_file(
-        &backup_dir
-            .join(group_key.to_string())
-            .join(height.to_string())
-            .join("catch_up_package.bin"),
-    );
-
-    let protobuf = ic_protobuf::types::v1::CatchUpPackage::decode(buffer.as_slice())
-        .expect("Protobuf decoding failed");
-
-    CatchUpPackage::try_from(&protobuf)
-        .unwrap_or_else(|_| panic!("{}", deserialization_error(height)))
+pub(crate) fn read_cup_at_height(source: &dyn BackupSource, height: Height) -> CatchUpPackage {
+    source
+        .read_cup(height)
+        .unwrap_or_else(|err| panic!("{}", err))
 }
 
-/// Read all files from the backup folder starting from the `start_height` and
+/// Read all files from the backup source starting from the `start_height` and
 /// convert them into batches.
 pub(super) fn heights_to_artifacts_metadata(
-    backup_dir: &Path,
+    source: &dyn BackupSource,
     start_height: Height,
 ) -> Result<BTreeMap<Height, HeightArtifacts>, std::io::Error> {
-    let mut results = Vec::new();
-    for group_dir in fs::read_dir(backup_dir)? {
-        for height_dir in fs::read_dir(group_dir?.path())? {
-            let path = height_dir?.path();
-            let height = Height::from(
-                path.file_name()
-                    .unwrap_or_default()
-                    .to_str()
-                    .unwrap_or_default()
-                    .parse::<u64>()
-                    .expect("Couldn't parse the height directory name"),
-            );
+    let mut results = BTreeMap::new();
+    for (height, files) in source.list_heights(start_height)? {
+        let get_files = |s| {
+            files
+                .iter()
+                .filter(|file| file.starts_with(s))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        results.insert(
+            height,
+            HeightArtifacts {
+                contains_cup: files.iter().any(|file| file == CUP_FILE_NAME),
+                contains_random_beacon: files.iter().any(|file| file == RANDOM_BEACON_FILE_NAME),
+                contains_random_tape: files.iter().any(|file| file == RANDOM_TAPE_FILE_NAME),
+                proposals: get_files("block_proposal"),
+                finalizations: get_files("finalization"),
+                notarizations: get_files("notarization"),
+            },
+        );
+    }
+    Ok(results)
+}
 
-            // Skip all height folders below the start height,
-            if height < start_height {
-                continue;
-            }
-            let mut files = Vec::new();
-            for file in fs::read_dir(&path)? {
-                let file_path = file?.path();
-                files.push(
-                    file_path
-                        .file_name()
-                        .unwrap_or_default()
-                        .to_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                );
-            }
-            let get_files = |s| {
-                files
-                    .iter()
-                    .filter(|file| file.starts_with(s))
-                    .cloned()
-                    .collect::<Vec<_>>()
-            };
+/// Returns all heights found in the backup starting from the `start_height`,
+/// together with the heights among them which contain a CUP.
+pub(crate) fn heights_and_cup_heights(
+    source: &dyn BackupSource,
+    start_height: Height,
+) -> Result<(Vec<Height>, Vec<Height>), std::io::Error> {
+    let artifacts = heights_to_artifacts_metadata(source, start_height)?;
+    let cup_heights = artifacts
+        .iter()
+        .filter(|(_, height_artifacts)| height_artifacts.contains_cup)
+        .map(|(height, _)| *height)
+        .collect();
+    Ok((artifacts.into_keys().collect(), cup_heights))
+}
+
+/// Returns the highest height starting from the `start_height` with a
+/// finalization in the backup, bounded by the `target_height` if given, i.e.
+/// the height of the last batch a restore delivers.
+pub(crate) fn last_finalized_height(
+    source: &dyn BackupSource,
+    start_height: Height,
+    target_height: Option<Height>,
+) -> Result<Option<Height>, std::io::Error> {
+    Ok(heights_to_artifacts_metadata(source, start_height)?
+        .into_iter()
+        .filter(|(height, artifacts)| {
+            !artifacts.finalizations.is_empty()
+                && target_height.map_or(true, |target| *height <= target)
+        })
+        .map(|(height, _)| height)
+        .last())
+}
+
+/// Returns the total size in bytes of all artifacts and the number of heights
+/// found in the backup directory starting from the `start_height`.
+pub(crate) fn artifacts_size(
+    source: &FsBackupSource,
+    start_height: Height,
+) -> Result<(u64, u64), std::io::Error> {
+    let mut bytes = 0;
+    let mut heights = 0;
+    for (height, files) in source.list_heights(start_height)? {
+        heights += 1;
+        let height_dir = source.height_dir(height);
+        for file in files {
+            bytes += fs::metadata(height_dir.join(file))?.len();
+        }
+    }
+    Ok((bytes, heights))
+}
+
+/// Scans the backup directory starting from the `start_height` and returns all
+/// heights containing conflicting artifacts.
+pub(crate) fn detect_conflicts(
+    source: &dyn BackupSource,
+    start_height: Height,
+) -> Result<Vec<(Height, ConflictKind)>, std::io::Error> {
+    // Artifact file names have the format `<type>_<block hash>_<artifact hash>.bin`.
+    let block_hash = |file_name: &String| file_name.split('_').nth(1).map(String::from);
+    let block_hash_of_proposal = |file_name: &String| file_name.split('_').nth(2).map(String::from);
+    let mut results = Vec::new();
+    for (height, height_artifacts) in heights_to_artifacts_metadata(source, start_height)? {
+        let finalized_blocks: BTreeSet<_> = height_artifacts
+            .finalizations
+            .iter()
+            .filter_map(block_hash)
+            .collect();
+        if finalized_blocks.len() > 1 {
             results.push((
                 height,
-                HeightArtifacts {
-                    path,
-                    contains_cup: !get_files("catch_up_package").is_empty(),
-                    proposals: get_files("block_proposal"),
-                    finalizations: get_files("finalization"),
-                    notarizations: get_files("notarization"),
-                },
+                ConflictKind::ConflictingFinalizations(finalized_blocks.into_iter().collect()),
             ));
         }
+        let mut proposals_by_block: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for file_name in height_artifacts.proposals.iter() {
+            if let Some(hash) = block_hash_of_proposal(file_name) {
+                proposals_by_block.entry(hash).or_default().push(file_name);
+            }
+        }
+        for (hash, file_names) in proposals_by_block {
+            if file_names.len() < 2 {
+                continue;
+            }
+            // Identical copies of the same proposal are not a conflict.
+            let mut contents = BTreeSet::new();
+            for file_name in file_names {
+                let content = source.read_artifact(height, file_name)?;
+                contents.insert(ic_crypto_sha::Sha256::hash(&content));
+            }
+            if contents.len() > 1 {
+                results.push((height, ConflictKind::DuplicateProposals(hash)));
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Writes the artifacts metadata of all heights in the backup to the given
+/// file in CSV format and returns the number of rows written. Each row
+/// contains the height, the types of the artifacts present, whether the height
+/// contains a CUP and the replica version of the spool.
+pub(crate) fn export_metadata_csv(
+    source: &dyn BackupSource,
+    replica_version: &ReplicaVersion,
+    dest: &Path,
+) -> Result<usize, std::io::Error> {
+    let artifacts = heights_to_artifacts_metadata(source, Height::from(0))?;
+    let mut writer = io::BufWriter::new(fs::File::create(dest)?);
+    writeln!(writer, "height,artifact_types,contains_cup,replica_version")?;
+    for (height, height_artifacts) in artifacts.iter() {
+        let types = [
+            (height_artifacts.contains_cup, "catch_up_package"),
+            (height_artifacts.contains_random_beacon, "random_beacon"),
+            (height_artifacts.contains_random_tape, "random_tape"),
+            (!height_artifacts.proposals.is_empty(), "block_proposal"),
+            (!height_artifacts.notarizations.is_empty(), "notarization"),
+            (!height_artifacts.finalizations.is_empty(), "finalization"),
+        ]
+        .iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+        writeln!(
+            writer,
+            "{},{},{},{}",
+            height, types, height_artifacts.contains_cup, replica_version
+        )?;
+    }
+    writer.flush()?;
+    Ok(artifacts.len())
+}
+
+/// Deletes all height directories strictly below the given height and returns
+/// the number of bytes reclaimed. The directory of the highest CUP below the
+/// given height is kept, so that a restore can still be started from it.
+/// Emptied group directories are deleted as well.
+pub(crate) fn purge_below(source: &FsBackupSource, height: Height) -> Result<u64, std::io::Error> {
+    let artifacts = heights_to_artifacts_metadata(source, Height::from(0))?;
+    let kept_cup_height = artifacts
+        .iter()
+        .filter(|(h, artifacts)| **h < height && artifacts.contains_cup)
+        .map(|(h, _)| *h)
+        .last();
+    let mut bytes = 0;
+    for (h, _) in artifacts {
+        if h >= height || Some(h) == kept_cup_height {
+            continue;
+        }
+        let height_dir = source.height_dir(h);
+        for file in fs::read_dir(&height_dir)? {
+            bytes += file?.metadata()?.len();
+        }
+        fs::remove_dir_all(&height_dir)?;
     }
-    Ok(results.into_iter().collect())
+    for group_dir in fs::read_dir(source.path())? {
+        let path = group_dir?.path();
+        if path.is_dir() && fs::read_dir(&path)?.next().is_none() {
+            fs::remove_dir(&path)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Returns the lowest height containing a CUP for each replica version found in
+/// the backup directory of a subnet, ordered by height.
+pub(crate) fn replica_version_start_heights(
+    subnet_backup_dir: &Path,
+) -> Result<Vec<(Height, String)>, std::io::Error> {
+    let mut results = Vec::new();
+    for version_dir in fs::read_dir(subnet_backup_dir)? {
+        let version_path = version_dir?.path();
+        if !version_path.is_dir() {
+            continue;
+        }
+        let version = version_path
+            .file_name()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+            .to_string();
+        let source = FsBackupSource::new(version_path);
+        let lowest_cup_height = heights_to_artifacts_metadata(&source, Height::from(0))?
+            .into_iter()
+            .find(|(_, artifacts)| artifacts.contains_cup)
+            .map(|(height, _)| height);
+        if let Some(height) = lowest_cup_height {
+            results.push((height, version));
+        }
+    }
+    results.sort();
+    Ok(results)
 }
 
 /// Deserializes consensus artifacts, reading them from the backup spool height
@@ -161,11 +477,12 @@ pub(super) fn heights_to_artifacts_metadata(
 pub(crate) fn deserialize_consensus_artifacts(
     registry_client: Arc<dyn RegistryClient>,
     pool: &mut ConsensusPoolImpl,
+    time_source: &dyn TimeSource,
+    source: &dyn BackupSource,
     height_to_batches: &mut BTreeMap<Height, HeightArtifacts>,
     subnet_id: SubnetId,
     latest_state_height: Height,
 ) -> ExitPoint {
-    let time_source = SysTimeSource::new();
     let mut last_cup_height: Option<Height> = None;
     let crypto =
         ic_crypto::CryptoComponentFatClient::new_for_verification_only(registry_client.clone());
@@ -188,7 +505,6 @@ pub(crate) fn deserialize_consensus_artifacts(
             last_cup_height = Some(height);
         }
 
-        let path = &height_artifacts.path;
         let mut artifacts = Vec::new();
 
         if height_artifacts.proposals.is_empty() {
@@ -210,7 +526,7 @@ pub(crate) fn deserialize_consensus_artifacts(
         if let Some(file_name) = &height_artifacts.finalizations.get(0) {
             // Save the hash of the finalized block proposal.
             finalized_block_hash = file_name.split('_').nth(1);
-            let buffer = read_file(&path.join(file_name));
+            let buffer = read_artifact(source, height, file_name);
             let finalization = Finalization::try_from(
                 pb::Finalization::decode(buffer.as_slice()).expect("Protobuf decoding failed"),
             )
@@ -241,7 +557,7 @@ pub(crate) fn deserialize_consensus_artifacts(
             // Otherwise, insert all.
             .filter(|name| name.contains(finalized_block_hash.unwrap_or("")))
         {
-            let buffer = read_file(&path.join(file_name));
+            let buffer = read_artifact(source, height, file_name);
             let proposal = BlockProposal::try_from(
                 pb::BlockProposal::decode(buffer.as_slice()).expect("Protobuf decoding failed"),
             )
@@ -281,15 +597,14 @@ pub(crate) fn deserialize_consensus_artifacts(
         }
 
         // Insert the random beacon and the random tape.
-        let rb_path = path.join("random_beacon.bin");
-        if !rb_path.exists() {
+        if !height_artifacts.contains_random_beacon {
             println!(
                 "Stopping deserialization at height {:?} as this height contains no random beacon.",
                 height,
             );
             return ExitPoint::Done;
         }
-        let buffer = read_file(&rb_path);
+        let buffer = read_artifact(source, height, RANDOM_BEACON_FILE_NAME);
         artifacts.push(
             RandomBeacon::try_from(
                 pb::RandomBeacon::decode(buffer.as_slice()).expect("Protobuf decoding failed"),
@@ -298,15 +613,14 @@ pub(crate) fn deserialize_consensus_artifacts(
             .into_message(),
         );
 
-        let rt_path = path.join("random_tape.bin");
-        if !rt_path.exists() {
+        if !height_artifacts.contains_random_tape {
             println!(
                 "Stopping deserialization at height {:?} as this height contains no random tape.",
                 height,
             );
             return ExitPoint::Done;
         }
-        let buffer = read_file(&rt_path);
+        let buffer = read_artifact(source, height, RANDOM_TAPE_FILE_NAME);
         artifacts.push(
             RandomTape::try_from(
                 pb::RandomTape::decode(buffer.as_slice()).expect("Protobuf decoding failed"),
@@ -317,7 +631,7 @@ pub(crate) fn deserialize_consensus_artifacts(
 
         // Insert the notarizations.
         for file_name in &height_artifacts.notarizations {
-            let buffer = read_file(&path.join(file_name));
+            let buffer = read_artifact(source, height, file_name);
             artifacts.push(
                 Notarization::try_from(
                     pb::Notarization::decode(buffer.as_slice()).expect("Protobuf decoding failed"),
@@ -333,7 +647,7 @@ pub(crate) fn deserialize_consensus_artifacts(
         );
 
         pool.apply_changes(
-            &time_source,
+            time_source,
             artifacts
                 .into_iter()
                 .map(ChangeAction::AddToValidated)
@@ -372,3 +686,260 @@ impl Verifier for MockVerifier {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_test_utilities::consensus::{fake::*, make_genesis};
+    use ic_types::consensus::dkg;
+
+    // Writes the given artifacts into the directory of the given height.
+    fn write_artifacts(backup_dir: &Path, height: u64, artifacts: &[(&str, &[u8])]) {
+        let group_key = (height / BACKUP_GROUP_SIZE) * BACKUP_GROUP_SIZE;
+        let height_dir = backup_dir
+            .join(group_key.to_string())
+            .join(height.to_string());
+        fs::create_dir_all(&height_dir).unwrap();
+        for (name, content) in artifacts {
+            fs::write(height_dir.join(name), content).unwrap();
+        }
+    }
+
+    #[test]
+    fn list_heights_skips_heights_below_start_height() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(tmp.path(), 1, &[(RANDOM_BEACON_FILE_NAME, b"1")]);
+        write_artifacts(
+            tmp.path(),
+            2,
+            &[
+                (RANDOM_BEACON_FILE_NAME, b"2"),
+                (RANDOM_TAPE_FILE_NAME, b"2"),
+            ],
+        );
+        write_artifacts(tmp.path(), BACKUP_GROUP_SIZE + 1, &[(CUP_FILE_NAME, b"3")]);
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        let mut heights = source.list_heights(Height::from(2)).unwrap();
+        heights.values_mut().for_each(|files| files.sort());
+        assert_eq!(
+            heights.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    Height::from(2),
+                    vec![
+                        RANDOM_BEACON_FILE_NAME.to_string(),
+                        RANDOM_TAPE_FILE_NAME.to_string()
+                    ]
+                ),
+                (
+                    Height::from(BACKUP_GROUP_SIZE + 1),
+                    vec![CUP_FILE_NAME.to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn last_finalized_height_ignores_unfinalized_heights_and_heights_above_target() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(tmp.path(), 1, &[("finalization_1.bin", b"1")]);
+        write_artifacts(tmp.path(), 2, &[("finalization_2.bin", b"2")]);
+        write_artifacts(tmp.path(), 3, &[("finalization_3.bin", b"3")]);
+        write_artifacts(tmp.path(), 4, &[("block_proposal_4.bin", b"4")]);
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        let last = |start, target: Option<u64>| {
+            last_finalized_height(&source, Height::from(start), target.map(Height::from)).unwrap()
+        };
+        assert_eq!(last(1, None), Some(Height::from(3)));
+        assert_eq!(last(1, Some(2)), Some(Height::from(2)));
+        assert_eq!(last(4, None), None);
+    }
+
+    #[test]
+    fn read_artifact_returns_the_same_content_for_all_buffer_sizes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let content: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        write_artifacts(tmp.path(), 7, &[(RANDOM_TAPE_FILE_NAME, &content)]);
+
+        for read_buffer_size in [None, Some(0), Some(7), Some(4096), Some(1 << 20)] {
+            let source = FsBackupSource::new(tmp.path().to_path_buf())
+                .with_read_buffer_size(read_buffer_size);
+            assert_eq!(
+                source
+                    .read_artifact(Height::from(7), RANDOM_TAPE_FILE_NAME)
+                    .unwrap(),
+                content,
+                "read buffer size {:?}",
+                read_buffer_size
+            );
+        }
+    }
+
+    #[test]
+    fn read_artifact_fails_for_missing_artifact() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(tmp.path(), 1, &[(RANDOM_BEACON_FILE_NAME, b"1")]);
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        assert!(source
+            .read_artifact(Height::from(1), RANDOM_TAPE_FILE_NAME)
+            .is_err());
+        assert!(source
+            .read_artifact(Height::from(2), RANDOM_BEACON_FILE_NAME)
+            .is_err());
+    }
+
+    #[test]
+    fn read_cup_decodes_the_stored_cup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cup = make_genesis(dkg::Summary::fake());
+        let bytes = pb::CatchUpPackage::from(&cup).encode_to_vec();
+        write_artifacts(tmp.path(), 0, &[(CUP_FILE_NAME, &bytes)]);
+        write_artifacts(tmp.path(), 1, &[(CUP_FILE_NAME, b"garbage")]);
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        assert_eq!(source.read_cup_bytes(Height::from(0)).unwrap(), bytes);
+        assert_eq!(source.read_cup(Height::from(0)).unwrap(), cup);
+        assert!(source.read_cup(Height::from(1)).is_err());
+        assert!(source.read_cup(Height::from(2)).is_err());
+    }
+
+    #[test]
+    fn detect_conflicts_reports_conflicting_finalizations_and_proposals() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(
+            tmp.path(),
+            1,
+            &[
+                ("finalization_aaa_1.bin", b"1"),
+                ("finalization_aaa_2.bin", b"2"),
+                ("block_proposal_ccc_1.bin", b"1"),
+            ],
+        );
+        write_artifacts(
+            tmp.path(),
+            2,
+            &[
+                ("finalization_aaa_1.bin", b"1"),
+                ("finalization_bbb_2.bin", b"2"),
+                ("block_proposal_ccc_1.bin", b"1"),
+                ("block_proposal_ccc_2.bin", b"2"),
+            ],
+        );
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        assert_eq!(
+            detect_conflicts(&source, Height::from(0)).unwrap(),
+            vec![
+                (
+                    Height::from(2),
+                    ConflictKind::ConflictingFinalizations(vec![
+                        "aaa".to_string(),
+                        "bbb".to_string()
+                    ])
+                ),
+                (
+                    Height::from(2),
+                    ConflictKind::DuplicateProposals("ccc".to_string())
+                ),
+            ]
+        );
+        assert!(detect_conflicts(&source, Height::from(3))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn detect_conflicts_ignores_identical_copies_of_a_proposal() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(
+            tmp.path(),
+            1,
+            &[
+                ("block_proposal_ccc_1.bin", b"proposal"),
+                ("block_proposal_ccc_2.bin", b"proposal"),
+            ],
+        );
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        assert!(detect_conflicts(&source, Height::from(0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn export_metadata_csv_writes_one_row_per_height() {
+        let tmp = tempfile::tempdir().unwrap();
+        let spool = tmp.path().join("spool");
+        write_artifacts(
+            &spool,
+            1,
+            &[(CUP_FILE_NAME, b"1"), (RANDOM_BEACON_FILE_NAME, b"1")],
+        );
+        write_artifacts(
+            &spool,
+            2,
+            &[
+                ("block_proposal_aaa_1.bin", b"2"),
+                ("notarization_aaa_1.bin", b"2"),
+                ("finalization_aaa_1.bin", b"2"),
+            ],
+        );
+        let source = FsBackupSource::new(spool);
+        let dest = tmp.path().join("metadata.csv");
+
+        let rows = export_metadata_csv(&source, &ReplicaVersion::default(), &dest).unwrap();
+
+        assert_eq!(rows, 2);
+        assert_eq!(
+            fs::read_to_string(&dest).unwrap(),
+            format!(
+                "height,artifact_types,contains_cup,replica_version\n\
+                 1,catch_up_package;random_beacon,true,{version}\n\
+                 2,block_proposal;notarization;finalization,false,{version}\n",
+                version = ReplicaVersion::default()
+            )
+        );
+    }
+
+    #[test]
+    fn purge_below_keeps_the_highest_cup_below_the_height() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_artifacts(tmp.path(), 1, &[(CUP_FILE_NAME, b"1")]);
+        write_artifacts(
+            tmp.path(),
+            BACKUP_GROUP_SIZE + 1,
+            &[(CUP_FILE_NAME, b"22"), (RANDOM_BEACON_FILE_NAME, b"22")],
+        );
+        write_artifacts(
+            tmp.path(),
+            BACKUP_GROUP_SIZE + 2,
+            &[(RANDOM_BEACON_FILE_NAME, b"333")],
+        );
+        write_artifacts(
+            tmp.path(),
+            BACKUP_GROUP_SIZE + 3,
+            &[(RANDOM_BEACON_FILE_NAME, b"4444")],
+        );
+        let source = FsBackupSource::new(tmp.path().to_path_buf());
+
+        let bytes = purge_below(&source, Height::from(BACKUP_GROUP_SIZE + 3)).unwrap();
+
+        assert_eq!(bytes, 1 + 3);
+        assert_eq!(
+            source
+                .list_heights(Height::from(0))
+                .unwrap()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![
+                Height::from(BACKUP_GROUP_SIZE + 1),
+                Height::from(BACKUP_GROUP_SIZE + 3)
+            ]
+        );
+        // The emptied group directory is deleted as well.
+        assert!(!tmp.path().join("0").exists());
+    }
+}
@@ -104,6 +104,10 @@ pub struct RestoreFromBackupCmd {
     pub replica_version: String,
     /// Height from which the restoration should happen
     pub start_height: u64,
+    /// Check that the replica version is blessed and matches the version
+    /// referenced by the CUP before restoring
+    #[clap(long)]
+    pub validate_replica_version: bool,
 }
 
 #[derive(Parser)]
@@ -220,7 +220,7 @@ pub fn cmd_set_recovery_cup(
 
     let time = context_time + Duration::from_secs(60);
     let state_hash = hex::decode(&cmd.state_hash).map_err(|err| format!("{}", err))?;
-    let cup = player.get_highest_catch_up_package();
+    let cup = player.get_highest_catch_up_package()?;
     let payload = cup.content.block.as_ref().payload.as_ref();
     let summary = payload.as_summary();
     let low_threshold_transcript = summary
@@ -55,6 +55,7 @@ pub mod player;
 ///         replica_version: "8b91ab7c6807a6e842d9e3bb943eadfaf856e082d1094c07852aef09f8cd0c93"
 ///             .to_string(),
 ///         start_height: 0,
+///         validate_replica_version: false,
 ///     })),
 /// };
 /// // Once the arguments are set well, the local store and spool directories are populated;
@@ -99,6 +100,12 @@ pub fn replay(args: ReplayToolArgs) -> ReplayResult {
                 )
                 .await
                 .with_replay_target_height(target_height);
+                if cmd.validate_replica_version {
+                    if let Err(err) = player.validate_replica_version() {
+                        println!("Invalid replica version:\n  {}", err);
+                        std::process::exit(1);
+                    }
+                }
                 *res_clone.borrow_mut() = player.restore(cmd.start_height + 1);
             });
             return;
@@ -153,7 +160,7 @@ pub fn replay(args: ReplayToolArgs) -> ReplayResult {
             if let Some(SubCommand::UpdateRegistryLocalStore) = subcmd {
                 player.update_registry_local_store()
             }
-            *res_clone.borrow_mut() = Ok(player.get_latest_state_height_and_hash());
+            *res_clone.borrow_mut() = player.get_latest_state_height_and_hash();
         })
     });
     let ret = result.borrow().clone();
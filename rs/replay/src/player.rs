@@ -1,29 +1,36 @@
 use crate::backup;
+pub use crate::backup::{BackupSource, ConflictKind, FsBackupSource};
 use ic_artifact_pool::{
     certification_pool::CertificationPoolImpl,
     consensus_pool::{ConsensusPoolImpl, UncachedConsensusPoolImpl},
 };
 use ic_config::{
-    artifact_pool::ArtifactPoolConfig, registry_client::DataProviderConfig,
+    artifact_pool::ArtifactPoolConfig, execution_environment::Config as HypervisorConfig,
+    registry_client::DataProviderConfig, state_manager::Config as StateManagerConfig,
     subnet_config::SubnetConfigs, Config,
 };
 use ic_consensus::consensus::{
     batch_delivery::deliver_batches, pool_reader::PoolReader, utils::crypto_hashable_to_seed,
 };
+use ic_constants::{MAX_INGRESS_TTL, PERMITTED_DRIFT};
 use ic_cycles_account_manager::CyclesAccountManager;
+use ic_error_types::RejectCode;
 use ic_execution_environment::ExecutionServices;
+use ic_ic00_types::CanisterStatusType;
 use ic_interfaces::crypto::ThresholdSigVerifierByPublicKey;
 use ic_interfaces::{
+    artifact_manager::ArtifactClient,
     certification::CertificationPool,
     certification::Verifier,
     execution_environment::{IngressHistoryReader, QueryHandler},
     messaging::{MessageRouting, MessageRoutingError},
     registry::{RegistryClient, RegistryDataProvider, RegistryTransportRecord},
+    time_source::{SysTimeSource, TimeSource},
 };
 use ic_interfaces_state_manager::{
     PermanentStateHashError, StateHashError, StateManager, StateReader,
 };
-use ic_logger::{new_replica_logger_from_config, ReplicaLogger};
+use ic_logger::{info, new_replica_logger_from_config, ReplicaLogger};
 use ic_messaging::MessageRoutingImpl;
 use ic_metrics::MetricsRegistry;
 use ic_nns_constants::REGISTRY_CANISTER_ID;
@@ -31,59 +38,502 @@ use ic_protobuf::registry::{
     replica_version::v1::BlessedReplicaVersions, subnet::v1::SubnetRecord,
 };
 use ic_registry_client::client::RegistryClientImpl;
-use ic_registry_client_helpers::deserialize_registry_value;
+use ic_registry_client_helpers::{
+    deserialize_registry_value,
+    subnet::{get_node_ids_from_subnet_record, SubnetListRegistry, SubnetRegistry},
+};
 use ic_registry_keys::{make_blessed_replica_version_key, make_subnet_record_key};
 use ic_registry_local_store::{
     Changelog, ChangelogEntry, KeyMutation, LocalStoreImpl, LocalStoreWriter,
 };
 use ic_registry_nns_data_provider::registry::registry_deltas_to_registry_transport_records;
+use ic_registry_proto_data_provider::ProtoRegistryDataProvider;
+use ic_registry_subnet_type::SubnetType;
 use ic_registry_transport::{
     deserialize_get_changes_since_response, deserialize_get_latest_version_response,
     deserialize_get_value_response, serialize_get_changes_since_request,
-    serialize_get_value_request,
+    serialize_get_value_request, Error as RegistryTransportError,
 };
 use ic_replica::setup::get_subnet_type;
 use ic_replicated_state::ReplicatedState;
-use ic_state_manager::StateManagerImpl;
+use ic_state_layout::{CheckpointLayout, ReadOnly, StateLayout};
+use ic_state_manager::{
+    manifest::{compute_manifest, manifest_hash, DEFAULT_CHUNK_SIZE},
+    ManifestMetrics, StateManagerImpl,
+};
 use ic_types::{
+    artifact::{StateSyncArtifactId, StateSyncMessage},
     batch::{Batch, BatchPayload, IngressPayload},
-    consensus::{catchup::CUPWithOriginalProtobuf, CatchUpPackage, HasHeight, HasVersion},
+    consensus::{
+        catchup::CUPWithOriginalProtobuf, Block, CatchUpPackage, HasHeight, HasVersion, Rank,
+    },
     ingress::{IngressState, IngressStatus, WasmResult},
     messages::{MessageId, SignedIngress, UserQuery},
+    state_sync::Manifest,
     time::current_time,
-    CryptoHashOfState, Height, PrincipalId, Randomness, RegistryVersion, ReplicaVersion, SubnetId,
-    Time, UserId,
+    CanisterId, CryptoHashOfState, Cycles, Height, NodeId, NumBytes, NumInstructions, PrincipalId,
+    Randomness, RegistryVersion, ReplicaVersion, SubnetId, Time, UserId,
 };
 use ic_types::{
     consensus::CatchUpContentProtobufBytes,
-    crypto::{CombinedThresholdSig, CombinedThresholdSigOf},
+    crypto::{threshold_sig::ThresholdSigPublicKey, CombinedThresholdSig, CombinedThresholdSigOf},
 };
+use prost::Message;
+use serde::Serialize;
 use slog_async::AsyncGuard;
 use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    io::Write,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    task::Poll,
+    time::{Duration, Instant},
 };
 use tempfile::TempDir;
 
+// Writes the formatted message to the output of the player.
+macro_rules! output {
+    ($player:expr, $($arg:tt)*) => {
+        $player.output(format_args!($($arg)*))
+    };
+}
+
+// Writes the formatted message followed by a newline to the output of the player.
+macro_rules! outputln {
+    ($player:expr) => {
+        output!($player, "\n")
+    };
+    ($player:expr, $fmt:expr) => {
+        output!($player, concat!($fmt, "\n"))
+    };
+    ($player:expr, $fmt:expr, $($arg:tt)*) => {
+        output!($player, concat!($fmt, "\n"), $($arg)*)
+    };
+}
+
 // Amount of time we are waiting for execution, after batches are delivered.
 const WAIT_DURATION: Duration = Duration::from_millis(500);
 
+// Number of waiting iterations after which the progress of the state
+// computation is logged.
+const WAIT_PROGRESS_INTERVAL: u64 = 20;
+
+// Default amount of time added to the current time to compute the ingress
+// expiry of queries.
+const DEFAULT_INGRESS_EXPIRY_GRACE: Duration = Duration::from_secs(60);
+
+// Default number of registry queries executed in parallel.
+const DEFAULT_MAX_PARALLEL_QUERIES: u32 = 8;
+
 /// Represents the height and the hash of the last execution state
 pub type StateParams = (Height, String);
 
+/// The machine-readable form of `StateParams`, e.g. for scripts asserting on
+/// the replayed state hash. The names of the fields are stable.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StateParamsJson {
+    pub height: u64,
+    pub state_hash: String,
+}
+
+impl From<&StateParams> for StateParamsJson {
+    fn from((height, state_hash): &StateParams) -> Self {
+        Self {
+            height: height.get(),
+            state_hash: state_hash.clone(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ReplayError {
-    /// Can't proceed because the state has diverged.
-    StateDivergence(Height),
+    /// Can't proceed because the local state hash at the given height differs
+    /// from the one in the CUP.
+    StateDivergence {
+        height: Height,
+        expected: CryptoHashOfState,
+        actual: CryptoHashOfState,
+    },
     /// Can't proceed because an upgrade was detected.
     UpgradeDetected(StateParams),
+    /// The height was not certified within the given time.
+    CertificationTimeout(Height),
+    /// The deadline passed before the replay finished. Contains the parameters
+    /// of the latest committed state.
+    DeadlineExceeded(StateParams),
+    /// The divergence handler requested to restart the replay from the
+    /// checkpoint at the given height.
+    RollbackRequested(Height),
+    /// Some of the extra ingress messages were not executed successfully.
+    /// Contains the summary of all extra messages.
+    ExtraMessagesFailed(ExtraMessagesSummary),
+    /// The given extra ingress message was not executed successfully, and the
+    /// player was configured to abort on the first reject.
+    ExtraMessageRejected {
+        message_id: MessageId,
+        reason: String,
+    },
+    /// Message routing doesn't expect the batch following the last delivered
+    /// batch, e.g. because the delivered batch was ignored.
+    BatchHeightMismatch {
+        delivered: Height,
+        diagnostics: BatchHeightDiagnostics,
+    },
+    /// The latest state height doesn't match the height of the last delivered
+    /// batch, although message routing accepted the batch.
+    StateHeightMismatch { expected: Height, actual: Height },
+    /// The state manager failed permanently to compute the state hash at the
+    /// given height.
+    StateComputationFailed { height: Height, detail: String },
+    /// The signature of the certification at the given height doesn't verify
+    /// against the public key of the subnet.
+    InvalidCertification(Height),
+    /// The requested time of the extra batch lies before the time of the
+    /// latest state.
+    InvalidExtraBatchTime { requested: Time, earliest: Time },
+    /// The backup spool is missing or couldn't be read.
+    BackupError(String),
+    /// The signature of the latest CUP doesn't verify against the public key
+    /// of the subnet or the given key.
+    InvalidCupSignature(String),
+    /// The player was created without a consensus pool.
+    NoConsensusPool,
+    /// The consensus pool was dropped by `release_pools` and is no longer
+    /// available.
+    PoolReleased,
+}
+
+/// The heights of the components involved in the batch delivery.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchHeightDiagnostics {
+    /// The height of the next batch expected by message routing.
+    pub expected_batch: Height,
+    /// The height of the latest state.
+    pub latest_state: Height,
+    /// The height of the latest certified state.
+    pub latest_certified: Height,
+    /// The finalized height of the consensus pool, if there is one.
+    pub finalized: Option<Height>,
+}
+
+/// The sizes of the queues of a single canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueSnapshot {
+    /// The number of messages in the input queues.
+    pub input_queue_len: usize,
+    /// The number of messages in the output queues.
+    pub output_queue_len: usize,
+    /// The number of reserved slots across the input and output queues.
+    pub reserved_slots: usize,
+}
+
+/// The status of a canister in the replayed state, equivalent to the status
+/// reported by the management canister.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanisterStatusSummary {
+    pub status: CanisterStatusType,
+    pub cycles: Cycles,
+    /// The memory used by the canister, including its message memory on
+    /// application subnets.
+    pub memory_size: NumBytes,
+    /// The SHA-256 hash of the installed Wasm module, if there is one.
+    pub module_hash: Option<Vec<u8>>,
+    pub controllers: Vec<PrincipalId>,
+}
+
+/// The result of comparing the manifest recomputed from the checkpoint at the
+/// latest CUP height with the manifest computed by the state manager.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestCheck {
+    pub height: Height,
+    /// The number of chunks with the same hash in both manifests.
+    pub matching_chunks: usize,
+    /// The number of chunks of the recomputed manifest.
+    pub total_chunks: usize,
+    /// The indices of the chunks whose hashes differ or which are missing
+    /// from one of the manifests.
+    pub differing_chunks: Vec<usize>,
+    /// True if the hash of the recomputed manifest matches the CUP.
+    pub matches_cup: bool,
+}
+
+/// A height at which the computed state hash differs from a reference hash.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashMismatch {
+    pub height: Height,
+    /// The reference state hash.
+    pub expected: String,
+    /// The computed state hash, or `None` if there is no state at this height.
+    pub actual: Option<String>,
+}
+
+/// The plan of a restore, computed from the backup metadata and the registry
+/// without delivering any batches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestorePlan {
+    /// The first and the last height to be restored, or `None` if the backup
+    /// contains no heights to restore. The last height is the expected final
+    /// height of the restore.
+    pub height_range: Option<(Height, Height)>,
+    /// The heights within the range containing a CUP.
+    pub cup_boundaries: Vec<Height>,
+    /// The CUP heights at which the registry version changes, together with
+    /// the new registry version.
+    pub registry_transitions: Vec<(Height, RegistryVersion)>,
+    /// The CUP heights from which on the restore has to be continued with the
+    /// replay tool of another replica version, together with that version.
+    pub version_switches_needed: Vec<(Height, ReplicaVersion)>,
+}
+
+/// A handle to pause and resume the batch delivery of a player, e.g. from an
+/// orchestrator running on another thread.
+#[derive(Clone, Default)]
+pub struct PauseHandle {
+    paused: Arc<Mutex<bool>>,
+    condvar: Arc<Condvar>,
+}
+
+impl PauseHandle {
+    /// Pauses the batch delivery before the next batches are delivered.
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    /// Resumes a paused batch delivery.
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.condvar.notify_all();
+    }
+
+    /// Returns true if the batch delivery is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    // Blocks as long as the batch delivery is paused.
+    fn wait_while_paused(&self) {
+        let _guard = self
+            .condvar
+            .wait_while(self.paused.lock().unwrap(), |paused| *paused)
+            .unwrap();
+    }
+}
+
+/// A non-fatal issue encountered during a replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayWarning {
+    /// The state diverged from the CUP at the given height, but the replay
+    /// continued.
+    DivergenceTolerated(Height),
+    /// The state hash was not compared with the CUP at the given height,
+    /// because newer states exist already.
+    CupComparisonSkipped(Height),
+    /// The delivery of batches up to the given height was retried the given
+    /// number of times, because the message routing queue was full.
+    QueueFull { height: Height, retries: usize },
+    /// The latest registry version could not be queried from the registry
+    /// canister and the version of the local registry was used instead.
+    RegistryVersionFallback(String),
+    /// There was no consensus pool, so no blocks were replayed.
+    NoConsensusPool,
+    /// The manifest recomputed at the given height differs from the manifest
+    /// of the state manager in the given chunks, although the hash of the
+    /// manifest matches the CUP.
+    ManifestChunksDiffer { height: Height, chunks: Vec<usize> },
+}
+
+/// A progress event of a replay.
+#[derive(Clone, Debug)]
+pub enum ReplayEvent {
+    /// All batches up to the given height were delivered.
+    BatchDelivered(Height),
+    /// The state at the given height was committed with the given hash.
+    CheckpointWritten(StateParams),
+    /// The restore stopped deserializing artifacts for the given reason.
+    ExitPoint(String),
+    /// The replay finished with the given result.
+    Finished(ReplayResult),
+}
+
+/// A summary of the execution outcomes of the extra ingress messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtraMessagesSummary {
+    /// Number of messages that completed with a reply.
+    pub succeeded: usize,
+    /// Number of rejected messages and their distinct reject messages, keyed
+    /// by reject code.
+    pub rejected: HashMap<RejectCode, (usize, BTreeSet<String>)>,
+    /// Messages whose execution did not complete, with their latest status.
+    pub unfinished: Vec<(MessageId, String)>,
+}
+
+impl ExtraMessagesSummary {
+    /// Returns true if all messages completed with a reply.
+    pub fn all_succeeded(&self) -> bool {
+        self.rejected.is_empty() && self.unfinished.is_empty()
+    }
+
+    fn add_reject(&mut self, code: RejectCode, message: String) {
+        let (count, messages) = self.rejected.entry(code).or_default();
+        *count += 1;
+        messages.insert(message);
+    }
+}
+
+impl fmt::Display for ExtraMessagesSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rejected: usize = self.rejected.values().map(|(count, _)| count).sum();
+        writeln!(
+            f,
+            "Extra messages: {} succeeded, {} rejected, {} unfinished",
+            self.succeeded,
+            rejected,
+            self.unfinished.len()
+        )?;
+        for (code, (count, messages)) in &self.rejected {
+            writeln!(f, "  {}: {} message(s)", code.to_string(), count)?;
+            for message in messages {
+                writeln!(f, "    {}", message)?;
+            }
+        }
+        for (msg_id, status) in &self.unfinished {
+            writeln!(f, "  {} has not finished: {}", msg_id, status)?;
+        }
+        Ok(())
+    }
+}
+
+/// The decision of a divergence handler on how to proceed after the local
+/// state diverged from the CUP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DivergenceAction {
+    /// Log the divergence and continue the replay.
+    Continue,
+    /// Abort the replay with a `StateDivergence` error.
+    Abort,
+    /// Abort the replay with a `RollbackRequested` error, so that the caller
+    /// can restart it from the checkpoint at the given height.
+    Rollback(Height),
 }
 
+/// A callback deciding how to proceed after a divergence, given the divergent
+/// height, the state hash of the CUP and the local state hash.
+pub type DivergenceHandler = Box<dyn FnMut(Height, &str, &str) -> DivergenceAction + Send>;
+
+/// A callback receiving the height, the block maker and the rank of each
+/// replayed finalized block.
+pub type BlockMetadataObserver = Box<dyn FnMut(Height, NodeId, Rank) + Send>;
+
 pub type ReplayResult = Result<StateParams, ReplayError>;
 
+/// The outcome of a successful replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayOutcome {
+    /// All finalized blocks were replayed with the replica version of this
+    /// player.
+    FullyReplayed(StateParams),
+    /// An upgrade was detected, so the remaining blocks have to be replayed
+    /// with the replay tool of the given replica version. Contains the
+    /// parameters of the latest state.
+    StoppedAtUpgrade {
+        params: StateParams,
+        required_version: ReplicaVersion,
+    },
+    /// There was no consensus pool, so no blocks were replayed and only the
+    /// extra messages were executed. Contains the parameters of the latest
+    /// state.
+    NoConsensusPool(StateParams),
+}
+
+impl ReplayOutcome {
+    /// Returns the parameters of the latest state.
+    pub fn into_params(self) -> StateParams {
+        match self {
+            ReplayOutcome::FullyReplayed(params) => params,
+            ReplayOutcome::StoppedAtUpgrade { params, .. } => params,
+            ReplayOutcome::NoConsensusPool(params) => params,
+        }
+    }
+}
+
+/// A preflight estimate of the disk space needed to restore from a backup.
+#[derive(Clone, Debug, Default)]
+pub struct BackupSizeEstimate {
+    /// Total size of all artifacts in the restored height range.
+    pub artifact_bytes: u64,
+    /// Number of heights in the restored height range.
+    pub height_count: u64,
+    /// Size of the checkpoint at the start height, or zero if there is none.
+    pub checkpoint_bytes: u64,
+    /// Rough lower bound of the peak size of the state directory, i.e. three
+    /// copies of the state grown by the artifacts.
+    pub estimated_state_bytes: u64,
+}
+
+// An append-only log, in which every entry contains the hash of the previous
+// entry, so that any modification of the log can be detected.
+struct AuditLog {
+    writer: Box<dyn Write + Send>,
+    last_entry_hash: [u8; 32],
+}
+
+impl AuditLog {
+    fn append(&mut self, entry: String) {
+        let line = format!("{} {}", hex::encode(self.last_entry_hash), entry);
+        writeln!(self.writer, "{}", line)
+            .and_then(|_| self.writer.flush())
+            .expect("Couldn't write to the audit log");
+        self.last_entry_hash = ic_crypto_sha::Sha256::hash(line.as_bytes());
+    }
+}
+
+// A registry value which is already encoded, so that it can be added to a
+// `ProtoRegistryDataProvider` as is.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct EncodedRegistryValue(Vec<u8>);
+
+impl Message for EncodedRegistryValue {
+    fn encode_raw<B>(&self, buf: &mut B)
+    where
+        B: prost::bytes::BufMut,
+    {
+        buf.put_slice(&self.0);
+    }
+
+    fn merge_field<B>(
+        &mut self,
+        tag: u32,
+        wire_type: prost::encoding::WireType,
+        buf: &mut B,
+        ctx: prost::encoding::DecodeContext,
+    ) -> Result<(), prost::DecodeError>
+    where
+        B: prost::bytes::Buf,
+    {
+        prost::encoding::skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
 /// The main ic-replay component that sets up consensus and execution
 /// environment to replay past blocks.
+///
+/// The builders overriding a part of the execution configuration set up the
+/// execution components again, so they have to be called before any batches
+/// are delivered.
 pub struct Player {
     state_manager: Arc<StateManagerImpl>,
     message_routing: MessageRoutingImpl,
@@ -103,18 +553,144 @@ pub struct Player {
     // The target height until which the state will be replayed.
     // None means finalized height.
     replay_target_height: Option<u64>,
+    // Set once the consensus and certification pools were dropped.
+    pools_released: bool,
+    // If true, a state divergence is only logged and the replay continues.
+    tolerate_divergence: bool,
+    // All heights at which a divergence was detected, together with the state
+    // hash of the CUP and the locally computed state hash.
+    divergence_log: RefCell<Vec<(Height, String, String)>>,
+    // If set, decides how to proceed after a divergence instead of the
+    // `tolerate_divergence` flag.
+    divergence_handler: RefCell<Option<DivergenceHandler>>,
+    // The time source used for all changes applied to the consensus pool.
+    time_source: Arc<dyn TimeSource>,
+    // If set, all processed CUPs and computed checkpoint hashes are recorded here.
+    audit_log: RefCell<Option<AuditLog>>,
+    // All human-facing messages are written here.
+    output: RefCell<Box<dyn Write + Send>>,
+    // If set, the state hash is verified against the latest CUP after every N
+    // new checkpoints during the restore.
+    verify_every: Option<u64>,
+    // Added to the current time to compute the ingress expiry of queries, which
+    // are not given an explicit one.
+    ingress_expiry_grace: Duration,
+    // If true, the extra batch uses the time of the last finalized block
+    // instead of bumping it by one nanosecond.
+    exact_extra_batch_time: bool,
+    // The metrics registry of the state manager and execution components.
+    metrics_registry: MetricsRegistry,
+    subnet_type: SubnetType,
+    state_manager_config: StateManagerConfig,
+    hypervisor_config: HypervisorConfig,
+    cycles_account_manager: Arc<CyclesAccountManager>,
+    warnings: RefCell<Vec<ReplayWarning>>,
+    // The source of the backup artifacts restored by `restore`.
+    backup_source: Option<Arc<dyn BackupSource>>,
+    // The context times of the first and the last delivered blocks.
+    replayed_time_range: RefCell<Option<(Time, Time)>>,
+    // If set, only ingress messages addressed to these canisters are delivered.
+    canister_allowlist: Option<HashSet<CanisterId>>,
+    // If set, the first extra message without a reply aborts the replay.
+    abort_on_first_reject: bool,
+    // If set, batches are delivered to this message routing, which skips
+    // execution, instead of the real one.
+    no_op_message_routing: Option<NoOpMessageRouting>,
+    // Receives the progress events of the replay.
+    event_sink: Option<Box<dyn Fn(ReplayEvent) + Send>>,
+    // Tolerated clock skew by which the ingress expiry window is widened on
+    // both ends.
+    ingress_expiry_clock_skew: Duration,
+    // If not set, the extra batch at the end of the replay doesn't create a
+    // checkpoint.
+    final_checkpoint: bool,
+    // The verifier of the state manager, kept to set up the state manager
+    // again with a different configuration.
+    verifier: Arc<dyn Verifier>,
+    // If set, the replay target is resolved at replay time to this fraction of
+    // the range between the latest state height and the finalized height.
+    replay_target_fraction: Option<f64>,
+    // Blocks the batch delivery while paused.
+    pause_handle: PauseHandle,
+    // The size of the buffer used to read the backup artifacts, if any.
+    backup_read_buffer_size: Option<usize>,
+    // The maximum number of registry queries executed in parallel.
+    max_parallel_queries: u32,
+    // Receives one JSON line with the execution inputs of every delivered batch.
+    execution_trace: RefCell<Option<Box<dyn Write + Send>>>,
+    // If set, the signatures of the certifications are verified before they are
+    // redelivered to the state manager.
+    strict_certification_redelivery: bool,
+    // If set, the batches from the consensus pool don't create checkpoints and
+    // the state hashes of the CUPs are not verified.
+    skip_intermediate_checkpoints: bool,
+    // If set while intermediate checkpoints are skipped, the batch at this
+    // height still creates a checkpoint.
+    final_checkpoint_height: Option<Height>,
+    // If set, the manifest at the latest CUP height is checked after the replay.
+    final_manifest_check: bool,
+    // The result of the latest final manifest check.
+    last_manifest_check: RefCell<Option<ManifestCheck>>,
+    // If set, overrides the instruction limit per message of the subnet config.
+    instruction_limit_override: Option<NumInstructions>,
+    // If set, the batch with extra messages uses this time.
+    extra_batch_time: Option<Time>,
+    // Receives the outcomes of the extra messages as a JSON array.
+    extra_results_sink: RefCell<Option<Box<dyn Write + Send>>>,
+    // If set, the restore overlaps the artifact deserialization with the state
+    // computation of the previously delivered batches.
+    pipelined_restore: bool,
+    // Receives the block maker and the rank of each replayed block.
+    block_metadata_observer: RefCell<Option<BlockMetadataObserver>>,
+    // Called with the height and the hex-encoded hash of each new checkpoint.
+    on_checkpoint: RefCell<Option<Box<dyn FnMut(Height, &str) + Send>>>,
+    // If set, no batches of blocks with a later time are delivered.
+    replay_target_time: Option<Time>,
+    // Called with the delivered height and the target height after each batch
+    // delivery.
+    progress_callback: Option<Box<dyn Fn(Height, Height) + Send>>,
+    // If set, the replay neither writes checkpoints nor removes older states.
+    dry_run: bool,
 }
 
 impl Player {
     /// Create and return a `Player` from a replica configuration object for
     /// restoring states from backups.
     pub async fn new_for_backup(
-        mut cfg: Config,
+        cfg: Config,
         replica_version: ReplicaVersion,
         backup_spool_path: &Path,
         registry_local_store_path: &Path,
         subnet_id: SubnetId,
         start_height: u64,
+    ) -> Self {
+        let backup_dir = backup_spool_path
+            .join(subnet_id.to_string())
+            .join(replica_version.to_string());
+        let mut player = Player::new_for_backup_with_source(
+            cfg,
+            replica_version,
+            Arc::new(FsBackupSource::new(backup_dir.clone())),
+            registry_local_store_path,
+            subnet_id,
+            start_height,
+        )
+        .await;
+        player.backup_dir = Some(backup_dir);
+        player
+    }
+
+    /// Like `new_for_backup`, but reads the backup artifacts of the subnet and
+    /// replica version from the given source instead of a local backup spool.
+    /// Operations which modify or scan the whole spool, like
+    /// `purge_backup_below`, are only supported for local backup spools.
+    pub async fn new_for_backup_with_source(
+        mut cfg: Config,
+        replica_version: ReplicaVersion,
+        backup_source: Arc<dyn BackupSource>,
+        registry_local_store_path: &Path,
+        subnet_id: SubnetId,
+        start_height: u64,
     ) -> Self {
         let (log, _async_log_guard) = new_replica_logger_from_config(&cfg.logger);
         let DataProviderConfig::LocalStore(local_store_from_config) = cfg
@@ -132,10 +708,12 @@ impl Player {
             let records = data_provider
                 .get_updates_since(RegistryVersion::from(0))
                 .expect("Couldn't get the initial registry contents");
+            // The player and its output don't exist yet.
             write_records_to_local_store(
                 local_store_from_config,
                 RegistryVersion::from(0),
                 records,
+                &mut std::io::stdout(),
             );
         }
 
@@ -158,11 +736,9 @@ impl Player {
             cfg.artifact_pool.consensus_pool_path
         );
         let artifact_pool_config = ArtifactPoolConfig::from(cfg.artifact_pool.clone());
-        let backup_dir = backup_spool_path
-            .join(subnet_id.to_string())
-            .join(replica_version.to_string());
         // Extract the genesis CUP and instantiate a new pool.
-        let initial_cup = backup::read_cup_at_height(&backup_dir, Height::from(start_height));
+        let initial_cup =
+            backup::read_cup_at_height(backup_source.as_ref(), Height::from(start_height));
         // This would create a new pool with just the genesis CUP.
         let pool = ConsensusPoolImpl::new_from_cup_without_bytes(
             subnet_id,
@@ -178,7 +754,7 @@ impl Player {
             registry,
             subnet_id,
             Some(pool),
-            Some(backup_dir),
+            Some(backup_source),
             replica_version,
             log,
             _async_log_guard,
@@ -188,6 +764,107 @@ impl Player {
         player
     }
 
+    /// Restore the state of the given subnet from the backup spool starting
+    /// from the genesis height, which cleans up the execution state and
+    /// writes the initial registry records to the local store before.
+    pub async fn replay_from_genesis(
+        cfg: Config,
+        replica_version: ReplicaVersion,
+        backup_spool_path: &Path,
+        registry_local_store_path: &Path,
+        subnet_id: SubnetId,
+    ) -> ReplayResult {
+        let start_height = 0;
+        let mut player = Player::new_for_backup(
+            cfg,
+            replica_version,
+            backup_spool_path,
+            registry_local_store_path,
+            subnet_id,
+            start_height,
+        )
+        .await;
+        player.restore(start_height + 1)
+    }
+
+    /// Estimate the disk space needed to restore the state of the given subnet
+    /// from the backup spool into the given state directory, without
+    /// constructing a `Player`.
+    ///
+    /// The state directory is needed in addition to the spool, because the
+    /// peak disk usage is dominated by the copies of the state, whose size is
+    /// derived from the checkpoint at the start height. The estimate is a
+    /// lower bound only, because execution can grow the state by far more than
+    /// the size of the restored artifacts.
+    pub fn estimate_backup_size(
+        backup_spool_path: &Path,
+        state_root: &Path,
+        subnet_id: SubnetId,
+        replica_version: &ReplicaVersion,
+        start_height: u64,
+    ) -> Result<BackupSizeEstimate, String> {
+        let backup_dir = backup_spool_path
+            .join(subnet_id.to_string())
+            .join(replica_version.to_string());
+        let (artifact_bytes, height_count) =
+            backup::artifacts_size(&FsBackupSource::new(backup_dir), Height::from(start_height))
+                .map_err(|err| format!("File scanning failed: {:?}", err))?;
+        let state_layout = StateLayout::new(
+            ic_logger::replica_logger::no_op_logger(),
+            state_root.to_path_buf(),
+        );
+        // There is no checkpoint e.g. when restoring from the genesis height.
+        let checkpoint_bytes = match state_layout.checkpoint(Height::from(start_height)) {
+            Ok(cp_layout) => dir_size(cp_layout.raw_path()).map_err(|err| {
+                format!(
+                    "Couldn't compute the size of the checkpoint at height {}: {:?}",
+                    start_height, err
+                )
+            })?,
+            Err(_) => 0,
+        };
+        Ok(BackupSizeEstimate {
+            artifact_bytes,
+            height_count,
+            checkpoint_bytes,
+            estimated_state_bytes: estimate_peak_state_bytes(checkpoint_bytes, artifact_bytes),
+        })
+    }
+
+    /// Copy the state directory configured in `cfg` into `scratch` and return a
+    /// config directing all state reads and writes to the copy, so that the
+    /// original state directory stays untouched. The progress is reported to
+    /// the given output. This has to be applied to the config before a `Player`
+    /// is created, because the state manager cleans up the state directory on
+    /// start.
+    pub fn overlay_state_config(
+        mut cfg: Config,
+        scratch: PathBuf,
+        output: &mut dyn Write,
+    ) -> Result<Config, String> {
+        let state_root = cfg.state_manager.state_root();
+        if scratch.starts_with(&state_root) {
+            return Err(format!(
+                "The scratch directory {:?} must not be inside the state directory {:?}",
+                scratch, state_root
+            ));
+        }
+        writeln!(
+            output,
+            "Copying the state directory {:?} to {:?}...",
+            state_root, scratch
+        )
+        .expect("Couldn't write to the output");
+        copy_dir_recursively(&state_root, &scratch).map_err(|err| {
+            format!(
+                "Couldn't copy the state directory {:?} to {:?}: {:?}",
+                state_root, scratch, err
+            )
+        })?;
+        cfg.state_manager = ic_config::state_manager::Config::new(scratch);
+        Ok(cfg)
+    }
+
     /// Create and return a `Player` from a replica configuration object for
     /// subnet recovery.
     pub async fn new(cfg: Config, subnet_id: SubnetId) -> Self {
@@ -231,6 +908,83 @@ impl Player {
         .await
     }
 
+    /// Detect the id of the subnet whose consensus pool is configured in `cfg`
+    /// from the DKG transcripts of the latest CUP in the pool. This fails for
+    /// the genesis CUP and for CUPs whose transcripts were dealt by another
+    /// subnet, e.g. the NNS right after the subnet creation or a recovery.
+    pub fn detect_subnet_id(cfg: &Config) -> Result<SubnetId, String> {
+        use ic_types::crypto::threshold_sig::ni_dkg::{NiDkgTag, NiDkgTargetSubnet};
+
+        if !cfg.artifact_pool.consensus_pool_path.exists() {
+            return Err(format!(
+                "No consensus pool found at {:?}",
+                cfg.artifact_pool.consensus_pool_path
+            ));
+        }
+        let mut artifact_pool_config = ArtifactPoolConfig::from(cfg.artifact_pool.clone());
+        artifact_pool_config.persistent_pool_read_only = true;
+        let consensus_pool = ConsensusPoolImpl::from_uncached(
+            UncachedConsensusPoolImpl::new(
+                artifact_pool_config,
+                ic_logger::replica_logger::no_op_logger(),
+            ),
+            MetricsRegistry::new(),
+        );
+        let cup = consensus_pool.get_cache().catch_up_package();
+        if cup.height() == Height::from(0) {
+            return Err("The subnet id cannot be detected from the genesis CUP".to_string());
+        }
+        let dkg_id = cup
+            .content
+            .block
+            .as_ref()
+            .payload
+            .as_ref()
+            .as_summary()
+            .dkg
+            .current_transcript(&NiDkgTag::HighThreshold)
+            .dkg_id;
+        if dkg_id.target_subnet != NiDkgTargetSubnet::Local {
+            return Err(format!(
+                "The transcripts of the CUP at height {} were dealt by the subnet {}",
+                cup.height(),
+                dkg_id.dealer_subnet
+            ));
+        }
+        Ok(dkg_id.dealer_subnet)
+    }
+
+    /// Like `new`, but with the subnet id detected from the consensus pool. If
+    /// a subnet id is given as well, it has to match the detected one.
+    pub async fn new_autodetect(cfg: Config, subnet_id: Option<SubnetId>) -> Result<Self, String> {
+        let detected_subnet_id = Player::detect_subnet_id(&cfg)?;
+        if let Some(subnet_id) = subnet_id {
+            if subnet_id != detected_subnet_id {
+                return Err(format!(
+                    "The given subnet id {} doesn't match the subnet id {} of the consensus pool",
+                    subnet_id, detected_subnet_id
+                ));
+            }
+        }
+        println!("Detected subnet id {}", detected_subnet_id);
+        Ok(Player::new(cfg, detected_subnet_id).await)
+    }
+
+    /// Create and return a `Player` for subnet recovery, which uses the given
+    /// query handler and ingress history reader instead of the ones set up by
+    /// the execution environment. Only meant to be used in tests.
+    pub async fn new_with_execution(
+        cfg: Config,
+        subnet_id: SubnetId,
+        query_handler: Arc<dyn QueryHandler<State = ReplicatedState>>,
+        ingress_history_reader: Box<dyn IngressHistoryReader>,
+    ) -> Self {
+        let mut player = Player::new(cfg, subnet_id).await;
+        player.http_query_handler = query_handler;
+        player.ingress_history_reader = ingress_history_reader;
+        player
+    }
+
     #[allow(clippy::too_many_arguments)]
     async fn new_with_params(
         cfg: Config,
@@ -238,7 +992,7 @@ impl Player {
         registry: Arc<RegistryClientImpl>,
         subnet_id: SubnetId,
         consensus_pool: Option<ConsensusPoolImpl>,
-        backup_dir: Option<PathBuf>,
+        backup_source: Option<Arc<dyn BackupSource>>,
         replica_version: ReplicaVersion,
         log: ReplicaLogger,
         _async_log_guard: AsyncGuard,
@@ -268,7 +1022,7 @@ impl Player {
             subnet_config.cycles_account_manager_config,
         ));
         let state_manager = Arc::new(StateManagerImpl::new(
-            verifier,
+            Arc::clone(&verifier),
             subnet_id,
             subnet_type,
             log.clone(),
@@ -277,33 +1031,22 @@ impl Player {
             None,
             ic_types::malicious_flags::MaliciousFlags::default(),
         ));
-        let execution_service = ExecutionServices::setup_execution(
-            log.clone(),
+        let (message_routing, http_query_handler, ingress_history_reader) = setup_execution(
+            &log,
             &metrics_registry,
             subnet_id,
             subnet_type,
-            subnet_config.scheduler_config,
             cfg.hypervisor.clone(),
             Arc::clone(&cycles_account_manager),
-            Arc::clone(&state_manager) as Arc<_>,
-        );
-        let message_routing = MessageRoutingImpl::new(
-            state_manager.clone(),
-            state_manager.clone(),
-            execution_service.ingress_history_writer.clone(),
-            execution_service.scheduler,
-            cfg.hypervisor,
-            cycles_account_manager,
-            subnet_id,
-            &metrics_registry,
-            log.clone(),
+            Arc::clone(&state_manager),
             registry.clone(),
+            None,
         );
         let certification_pool = if consensus_pool.is_some() {
             Some(CertificationPoolImpl::new(
                 ArtifactPoolConfig::from(cfg.artifact_pool.clone()),
                 log.clone(),
-                metrics_registry,
+                metrics_registry.clone(),
             ))
         } else {
             None
@@ -313,148 +1056,1385 @@ impl Player {
             state_manager,
             message_routing,
             consensus_pool,
-            http_query_handler: execution_service.sync_query_handler,
-            ingress_history_reader: execution_service.ingress_history_reader,
+            http_query_handler,
+            ingress_history_reader,
             certification_pool,
             registry,
             local_store_path,
             subnet_id,
             replica_version,
-            backup_dir,
+            backup_dir: None,
             log,
             _async_log_guard,
             tmp_dir: None,
             replay_target_height: None,
+            pools_released: false,
+            tolerate_divergence: false,
+            divergence_log: RefCell::new(Vec::new()),
+            divergence_handler: RefCell::new(None),
+            time_source: Arc::new(SysTimeSource::new()),
+            audit_log: RefCell::new(None),
+            output: RefCell::new(Box::new(std::io::stdout())),
+            verify_every: None,
+            ingress_expiry_grace: DEFAULT_INGRESS_EXPIRY_GRACE,
+            exact_extra_batch_time: false,
+            metrics_registry,
+            subnet_type,
+            state_manager_config: cfg.state_manager,
+            hypervisor_config: cfg.hypervisor,
+            cycles_account_manager,
+            warnings: RefCell::new(Vec::new()),
+            backup_source,
+            replayed_time_range: RefCell::new(None),
+            canister_allowlist: None,
+            abort_on_first_reject: false,
+            no_op_message_routing: None,
+            event_sink: None,
+            ingress_expiry_clock_skew: Duration::from_secs(0),
+            final_checkpoint: true,
+            verifier,
+            replay_target_fraction: None,
+            pause_handle: PauseHandle::default(),
+            backup_read_buffer_size: None,
+            max_parallel_queries: DEFAULT_MAX_PARALLEL_QUERIES,
+            execution_trace: RefCell::new(None),
+            strict_certification_redelivery: false,
+            skip_intermediate_checkpoints: false,
+            final_checkpoint_height: None,
+            final_manifest_check: false,
+            last_manifest_check: RefCell::new(None),
+            instruction_limit_override: None,
+            extra_batch_time: None,
+            extra_results_sink: RefCell::new(None),
+            pipelined_restore: false,
+            block_metadata_observer: RefCell::new(None),
+            on_checkpoint: RefCell::new(None),
+            replay_target_time: None,
+            progress_callback: None,
+            dry_run: false,
         }
     }
 
-    /// Set the replay target height
-    pub fn with_replay_target_height(mut self, replay_target_height: Option<u64>) -> Self {
-        self.replay_target_height = replay_target_height;
+    /// Override the hypervisor config, a mismatch is a common cause of a `StateDivergence`.
+    pub fn with_hypervisor_config(mut self, hypervisor_config: HypervisorConfig) -> Self {
+        self.hypervisor_config = hypervisor_config;
+        self.setup_components();
         self
     }
 
-    /// Replay past finalized but un-executed blocks by delivering ingress
-    /// messages for execution, and make a full checkpoint of the latest
-    /// state when they all finish.
-    ///
-    /// It takes a function argument, which can be used to make extra ingress
-    /// messages for execution, which are delivered after the last finalized
-    /// block has been replayed. Note that this will advance the executed
-    /// batch height but not advance finalized block height in consensus
-    /// pool.
-    pub fn replay<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(&self, extra: F) -> ReplayResult {
-        if let (Some(consensus_pool), Some(certification_pool)) =
-            (&self.consensus_pool, &self.certification_pool)
-        {
-            match self.verify_latest_cup() {
-                Err(ReplayError::UpgradeDetected(_)) | Ok(_) => {}
-                other => other?,
-            };
-            let pool_reader = &PoolReader::new(consensus_pool);
-            let finalized_height = pool_reader.get_finalized_height();
-            let target_height = Some(
-                finalized_height.min(
-                    self.replay_target_height
-                        .map(Height::from)
-                        .unwrap_or_else(|| finalized_height),
-                ),
-            );
-            let last_batch_height =
-                self.deliver_batches(&self.message_routing, pool_reader, target_height);
-            self.wait_for_state(last_batch_height);
-            // We only want to persist the checkpoint after the latest batch.
-            self.state_manager.remove_states_below(last_batch_height);
-
-            // Redeliver certifications to state manager. It will panic if there is any
-            // mismatch.
-            print!("Redelivering certifications:");
-            for h in certification_pool.certified_heights() {
-                let certification = certification_pool
-                    .certification_at_height(h)
-                    .unwrap_or_else(|| panic!("Missing certification at height {:?}", h));
-                self.state_manager
-                    .deliver_state_certification(certification);
-                print!(" {}", h);
+    /// Override the state manager config, i.e. the state root the states are
+    /// loaded from and checkpointed to. This fails if the consensus pool
+    /// already contains artifacts above its CUP.
+    pub fn with_state_manager_config(
+        mut self,
+        state_manager_config: StateManagerConfig,
+    ) -> Result<Self, String> {
+        if let Some(pool) = &self.consensus_pool {
+            let cup_height = pool.get_cache().catch_up_package().height();
+            if PoolReader::new(pool).get_finalized_height() > cup_height {
+                return Err(format!(
+                    "The state manager config can't be changed once the consensus pool is initialised above the CUP at height {}",
+                    cup_height
+                ));
             }
-            println!();
-            println!("All blocks successfully replayed.");
         }
+        self.state_manager_config = state_manager_config;
+        self.setup_components();
+        Ok(self)
+    }
 
-        let (latest_context_time, extra_batch_delivery) =
-            self.deliver_extra_batch(&self.message_routing, self.consensus_pool.as_ref(), extra);
+    /// Override the cycles account manager, which is required to replay across cycle cost changes.
+    pub fn with_cycles_account_manager(
+        mut self,
+        cycles_account_manager: Arc<CyclesAccountManager>,
+    ) -> Self {
+        self.cycles_account_manager = cycles_account_manager;
+        self.setup_components();
+        self
+    }
 
-        if let Some((last_batch_height, msg_ids)) = extra_batch_delivery {
-            self.wait_for_state(last_batch_height);
-            // We only want to persist the checkpoint after the latest batch.
-            self.state_manager.remove_states_below(last_batch_height);
+    /// Override the instructions per message limit for what-if analyses, the states then diverge.
+    pub fn with_instruction_limit_override(mut self, max_instructions: NumInstructions) -> Self {
+        self.instruction_limit_override = Some(max_instructions);
+        self.setup_components();
+        self
+    }
 
-            // check if the extra messages have been delivered successfully
-            let get_latest_status = self.ingress_history_reader.get_latest_status();
-            for msg_id in msg_ids {
-                match get_latest_status(&msg_id) {
-                    IngressStatus::Known {
-                        state: IngressState::Completed(WasmResult::Reply(bytes)),
-                        ..
-                    } => println!("Ingress id={} response={}", &msg_id, hex::encode(bytes)),
-                    status => panic!("Execution of {} has failed: {:?}", msg_id, status),
-                }
-            }
+    // Sets up the state manager and the execution components again with the
+    // current configuration. All builders overriding a part of the execution
+    // configuration call this, so they have to be called before any batches
+    // are delivered, because the components set up before are discarded
+    // together with their in-memory states and queues. Metrics can't be
+    // registered twice, so the new components register them with a new
+    // metrics registry, which replaces the one of the player. Only the pools
+    // keep reporting to the registry they were created with.
+    fn setup_components(&mut self) {
+        self.metrics_registry = MetricsRegistry::new();
+        self.state_manager = Arc::new(StateManagerImpl::new(
+            Arc::clone(&self.verifier),
+            self.subnet_id,
+            self.subnet_type,
+            self.log.clone(),
+            &self.metrics_registry,
+            &self.state_manager_config,
+            None,
+            ic_types::malicious_flags::MaliciousFlags::default(),
+        ));
+        let (message_routing, http_query_handler, ingress_history_reader) = setup_execution(
+            &self.log,
+            &self.metrics_registry,
+            self.subnet_id,
+            self.subnet_type,
+            self.hypervisor_config.clone(),
+            Arc::clone(&self.cycles_account_manager),
+            Arc::clone(&self.state_manager),
+            self.registry.clone(),
+            self.instruction_limit_override,
+        );
+        self.message_routing = message_routing;
+        self.http_query_handler = http_query_handler;
+        self.ingress_history_reader = ingress_history_reader;
+    }
+
+    /// Replace the registry with one backed by the given records held in
+    /// memory, e.g. for hermetic tests. The execution components are set up
+    /// again with the new registry, and the registry local store is not used
+    /// anymore.
+    pub fn with_in_memory_registry(mut self, records: Vec<RegistryTransportRecord>) -> Self {
+        let data_provider = Arc::new(ProtoRegistryDataProvider::new());
+        for record in records {
+            data_provider
+                .add(
+                    &record.key,
+                    record.version,
+                    record.value.map(EncodedRegistryValue),
+                )
+                .unwrap_or_else(|err| panic!("Couldn't add the registry record: {}", err));
         }
+        let registry = Arc::new(RegistryClientImpl::new(data_provider, None));
+        registry
+            .poll_once()
+            .expect("Couldn't poll the registry data provider");
+        self.registry = registry;
+        self.local_store_path = None;
+        self.setup_components();
+        self
+    }
 
-        // If we are not replaying NNS subnet, this query will fail.
-        // If it fails, we'll query registry client for latest version instead.
-        let registry_version = self
-            .get_latest_registry_version(latest_context_time)
-            .unwrap_or_else(|_| self.registry.get_latest_version());
-        println!("Latest registry version: {}", registry_version);
-        Ok(self.get_latest_state_height_and_hash())
+    /// Set the replay target height
+    pub fn with_replay_target_height(mut self, replay_target_height: Option<u64>) -> Self {
+        self.replay_target_height = replay_target_height;
+        self
     }
 
-    // Blocks until the state at the given height is committed.
-    fn wait_for_state(&self, height: Height) {
-        loop {
-            // We first check if `height` was executed. Otherwise the state manager
-            // would return a permanent error on a too big height.
-            if self.state_manager.latest_state_height() >= height {
-                if let Some(hash) = get_state_hash(&*self.state_manager, height) {
-                    println!("Latest checkpoint at height: {}", height);
-                    println!("Latest state hash: {}", hex::encode(&hash.get().0));
-                };
-                break;
-            }
-            std::thread::sleep(WAIT_DURATION);
-        }
-        println!(
-            "Latest state height is {}",
-            self.state_manager.latest_state_height()
-        );
-        assert_eq!(
-            height,
-            self.state_manager.latest_state_height(),
-            "Latest delivered batch is of height {} while the latest known state is at height {}",
-            height,
-            self.state_manager.latest_state_height()
+    /// Stop the replay before the first finalized block whose time exceeds
+    /// the given time. If a target height is set as well, the replay stops at
+    /// whichever is reached first.
+    pub fn with_replay_target_time(mut self, replay_target_time: Option<Time>) -> Self {
+        self.replay_target_time = replay_target_time;
+        self
+    }
+
+    /// Set the replay target to the given fraction of the range between the
+    /// latest state height and the finalized height, which is resolved when
+    /// the replay starts. The resulting height is rounded down, so `0.0`
+    /// delivers no batches and `1.0` replays up to the finalized height. If a
+    /// replay target height is set as well, the lower of both is used.
+    ///
+    /// Panics if the fraction is not within `0.0..=1.0`.
+    pub fn with_replay_target_fraction(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "The replay target fraction {} is not within 0.0..=1.0",
+            fraction
         );
+        self.replay_target_fraction = Some(fraction);
+        self
+    }
+
+    /// If set, the restore doesn't wait for the state computation of the
+    /// delivered batches before deserializing the next artifacts, as long as
+    /// the deserialization only stopped because the state is behind. It then
+    /// only waits until the certified height referenced by the next block is
+    /// executed. Batches are still delivered in strict order, and the restore
+    /// waits for all delivered batches at CUP boundaries, registry updates,
+    /// the target height and the end of the backup spool.
+    pub fn with_pipelined_restore(mut self, pipelined_restore: bool) -> Self {
+        self.pipelined_restore = pipelined_restore;
+        self
+    }
+
+    /// Read the artifacts of the backup spool in chunks of the given size in
+    /// bytes, so that each read call transfers up to that many bytes, e.g. to
+    /// issue fewer, larger requests on a spool mounted from a network file
+    /// system. The `backup_source` benchmark measures the effect on a given
+    /// file system. This has no effect on backup sources other than the
+    /// backup directory of the player.
+    pub fn with_backup_read_buffer_size(mut self, read_buffer_size: usize) -> Self {
+        self.backup_read_buffer_size = Some(read_buffer_size);
+        if let Some(backup_dir) = &self.backup_dir {
+            self.backup_source = Some(Arc::new(
+                FsBackupSource::new(backup_dir.clone())
+                    .with_read_buffer_size(Some(read_buffer_size)),
+            ));
+        }
+        self
+    }
+
+    /// Set the maximum number of registry queries executed in parallel by
+    /// `registry_records`. Defaults to 8.
+    pub fn with_max_parallel_queries(mut self, max_parallel_queries: u32) -> Self {
+        self.max_parallel_queries = max_parallel_queries.max(1);
+        self
+    }
+
+    /// Set the time source used for changes applied to the consensus pool.
+    pub fn with_time_source(mut self, time_source: Arc<dyn TimeSource>) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    /// Override the replica version used to deliver batches and to detect
+    /// upgrades. Forcing a version that does not match the one of the replayed
+    /// artifacts is the point of this override, e.g. to deliberately trigger
+    /// an `UpgradeDetected` error in tests.
+    pub fn with_replica_version(mut self, replica_version: ReplicaVersion) -> Self {
+        outputln!(
+            self,
+            "Overriding the replica version with {}",
+            replica_version
+        );
+        self.replica_version = replica_version;
+        self
+    }
+
+    /// Only deliver the ingress messages of finalized blocks addressed to the
+    /// given canisters, e.g. to isolate the behavior of a single canister.
+    /// Note that this makes the replayed state diverge from the state of the
+    /// subnet, so its state hash won't match the one in the CUP. This is
+    /// meant for analysis only.
+    pub fn with_canister_allowlist(mut self, canister_allowlist: HashSet<CanisterId>) -> Self {
+        outputln!(
+            self,
+            "⚠️  Only replaying ingress messages to {} canister(s); the resulting state will diverge from the subnet's state",
+            canister_allowlist.len()
+        );
+        self.canister_allowlist = Some(canister_allowlist);
+        self
+    }
+
+    /// Write the outcomes of the extra messages to the given writer as a JSON
+    /// array, with one object per message containing the `message_id`, the
+    /// `status` (`replied`, `rejected` or `unfinished`) and either the
+    /// `reply_hex` or the `reject_code` and `reject_message`.
+    pub fn with_extra_results_sink(self, writer: Box<dyn Write + Send>) -> Self {
+        *self.extra_results_sink.borrow_mut() = Some(writer);
+        self
+    }
+
+    // Writes the given outcomes of the extra messages to the results sink, if
+    // there is one.
+    fn write_extra_results(&self, results: Vec<serde_json::Value>) {
+        if let Some(writer) = self.extra_results_sink.borrow_mut().as_mut() {
+            serde_json::to_writer_pretty(&mut *writer, &results)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.flush())
+                .expect("Couldn't write to the extra results sink");
+        }
+    }
+
+    /// If set, the replay returns `ExtraMessageRejected` on the first extra
+    /// message without a reply. Otherwise, the outcomes of all extra messages
+    /// are collected and `ExtraMessagesFailed` is returned if any of them
+    /// failed.
+    pub fn with_abort_on_first_reject(mut self, abort_on_first_reject: bool) -> Self {
+        self.abort_on_first_reject = abort_on_first_reject;
+        self
+    }
+
+    /// Widens the ingress expiry window on both ends by the given clock skew.
+    /// This allows to validate historical ingress messages whose expiry lies
+    /// far from the clock of the machine running the replay.
+    pub fn with_ingress_expiry_clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.ingress_expiry_clock_skew = clock_skew;
+        self
+    }
+
+    /// If set, batches are delivered to a message routing which only advances
+    /// the batch height and skips execution. This allows to validate the
+    /// consensus artifacts, e.g. the CUP signatures and the block chain, much
+    /// faster. Since no states are produced, the comparison of state hashes
+    /// with the CUPs is skipped and the latest CUP is reported instead of the
+    /// latest state.
+    pub fn with_consensus_only(mut self, consensus_only: bool) -> Self {
+        self.no_op_message_routing = if consensus_only {
+            Some(NoOpMessageRouting {
+                expected_batch_height: AtomicU64::new(
+                    self.message_routing.expected_batch_height().get(),
+                ),
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    // Returns the message routing batches are delivered to.
+    fn message_routing(&self) -> &dyn MessageRouting {
+        match &self.no_op_message_routing {
+            Some(message_routing) => message_routing,
+            None => &self.message_routing,
+        }
+    }
+
+    // Returns the height up to which batches were executed. In consensus only
+    // mode, this is the height of the last delivered batch.
+    fn executed_height(&self) -> Height {
+        match &self.no_op_message_routing {
+            Some(message_routing) => message_routing.expected_batch_height().decrement(),
+            None => self.state_manager.latest_state_height(),
+        }
+    }
+
+    /// If set, the batch with extra messages uses the time of the last
+    /// finalized block unchanged instead of bumping it by one nanosecond.
+    /// This is meant for advanced use only, because message routing may
+    /// reject a batch with a non-increasing time.
+    pub fn with_exact_extra_batch_time(mut self, exact_extra_batch_time: bool) -> Self {
+        self.exact_extra_batch_time = exact_extra_batch_time;
+        self
+    }
+
+    /// If set, the batch with extra messages uses the given time, which is
+    /// also passed to the function making the extra messages. This allows to
+    /// reproduce the behavior of canisters depending on a specific historical
+    /// time. The time must not lie before the time of the latest state,
+    /// otherwise the replay fails with `InvalidExtraBatchTime`. This takes
+    /// precedence over `with_exact_extra_batch_time`.
+    pub fn with_extra_batch_time(mut self, extra_batch_time: Option<Time>) -> Self {
+        self.extra_batch_time = extra_batch_time;
+        self
+    }
+
+    /// If set, the manifest of the checkpoint at the latest CUP height is
+    /// recomputed from disk after the replay and compared chunk by chunk with
+    /// the manifest computed by the state manager. The result is available via
+    /// `last_manifest_check`. Differing chunks despite a manifest hash matching
+    /// the CUP are reported as a warning.
+    pub fn with_final_manifest_check(mut self, final_manifest_check: bool) -> Self {
+        self.final_manifest_check = final_manifest_check;
+        self
+    }
+
+    /// Return the result of the manifest check of the latest replay, if it
+    /// was enabled and the checkpoint at the latest CUP height was available.
+    pub fn last_manifest_check(&self) -> Option<ManifestCheck> {
+        self.last_manifest_check.borrow().clone()
+    }
+
+    // Compares the manifest recomputed from the checkpoint at the latest CUP
+    // height with the manifest computed by the state manager.
+    fn check_final_manifest(&self) -> Result<ManifestCheck, String> {
+        let cup = self
+            .get_latest_cup()
+            .map_err(|err| format!("{:?}", err))?
+            .cup;
+        let height = cup.height();
+        let recomputed = self.export_manifest(height)?;
+        let stored = self.state_sync_artifact(height)?.manifest;
+        let total_chunks = recomputed.chunk_table.len();
+        let differing_chunks = differing_manifest_chunks(&recomputed, &stored);
+        let matching_chunks = total_chunks.max(stored.chunk_table.len()) - differing_chunks.len();
+        Ok(ManifestCheck {
+            height,
+            matching_chunks,
+            total_chunks,
+            differing_chunks,
+            matches_cup: manifest_hash(&recomputed).to_vec() == cup.content.state_hash.get_ref().0,
+        })
+    }
+
+    /// If unset, the extra batch delivered at the end of the replay does not
+    /// create a checkpoint, which makes iterative analysis runs faster. The
+    /// replay then reports the hash of the partial in-memory state instead.
+    /// Note that such a replay is not resumable: the latest state is lost once
+    /// the player is dropped. Defaults to `true`.
+    pub fn with_final_checkpoint(mut self, final_checkpoint: bool) -> Self {
+        self.final_checkpoint = final_checkpoint;
+        self
+    }
+
+    /// Set whether the batch with extra messages requires a full state hash.
+    /// If unset, the results of the extra messages can be inspected in memory
+    /// without writing a checkpoint, but they are not durable. This is the
+    /// same as [`Player::with_final_checkpoint`]. Defaults to `true`.
+    pub fn with_extra_batch_full_hash(self, full_hash: bool) -> Self {
+        self.with_final_checkpoint(full_hash)
+    }
+
+    /// If set, `replay` computes the state hashes without writing checkpoints
+    /// or removing older states, e.g. to audit a backup without modifying the
+    /// state on disk. The returned parameters contain the partial hash of the
+    /// latest in-memory state. Note that the state manager still needs a
+    /// writable state directory for its temporary files.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    // Returns true if the batch with extra messages creates a checkpoint.
+    fn persists_final_checkpoint(&self) -> bool {
+        self.final_checkpoint && !self.dry_run
+    }
+
+    /// Set the amount of time added to the current time to compute the ingress
+    /// expiry of queries without an explicit expiry.
+    pub fn with_default_ingress_expiry_grace(mut self, grace: Duration) -> Self {
+        self.ingress_expiry_grace = grace;
+        self
+    }
+
+    // Returns the ingress expiry for queries without an explicit expiry.
+    fn default_ingress_expiry(&self) -> Time {
+        current_time() + self.ingress_expiry_grace
+    }
+
+    /// Verify every `n`-th new checkpoint during the restore by comparing the
+    /// state hash of the nearest CUP at or below it in the backup spool with
+    /// the checkpoint this CUP certifies. `None` only verifies the state when a
+    /// new CUP is inserted.
+    pub fn with_verify_every(mut self, verify_every: Option<u64>) -> Self {
+        self.verify_every = verify_every;
+        self
+    }
+
+    /// Write all human-facing messages to the given writer instead of stdout.
+    pub fn with_output(self, output: Box<dyn Write + Send>) -> Self {
+        *self.output.borrow_mut() = output;
+        self
+    }
+
+    /// Append one JSON line per delivered batch to the given writer, containing
+    /// the inputs relevant for the execution: the batch number, time,
+    /// randomness, registry version and the ids of the ingress messages. Two
+    /// replays of the same backup produce identical traces, so a diff between
+    /// the traces of divergent runs shows the first batch whose inputs differ.
+    pub fn with_execution_trace(self, writer: Box<dyn Write + Send>) -> Self {
+        *self.execution_trace.borrow_mut() = Some(writer);
+        self
+    }
+
+    // Returns the state hash for the given height once it is computed. For non-checkpoints heights
+    // `None` is returned. Only transient errors are retried; permanent errors are returned as
+    // `ReplayError::StateComputationFailed`.
+    fn get_state_hash(&self, height: Height) -> Result<Option<CryptoHashOfState>, ReplayError> {
+        loop {
+            if let Poll::Ready(result) = self.poll_state_hash(height) {
+                return result;
+            }
+            std::thread::sleep(WAIT_DURATION);
+        }
+    }
+
+    // Returns the state hash for the given height if it is computed already,
+    // with the same semantics as `get_state_hash`, or `Poll::Pending` on a
+    // transient error.
+    fn poll_state_hash(
+        &self,
+        height: Height,
+    ) -> Poll<Result<Option<CryptoHashOfState>, ReplayError>> {
+        match self.state_manager.get_state_hash_at(height) {
+            Ok(hash) => Poll::Ready(Ok(Some(hash))),
+            Err(StateHashError::Transient(err)) => {
+                outputln!(
+                    self,
+                    "Waiting for state hash at height {}: {:?}",
+                    height,
+                    err
+                );
+                Poll::Pending
+            }
+            // This only happens for partially certified heights.
+            Err(StateHashError::Permanent(PermanentStateHashError::StateNotFullyCertified(h)))
+                if h == height =>
+            {
+                Poll::Ready(Ok(None))
+            }
+            Err(StateHashError::Permanent(err)) => {
+                Poll::Ready(Err(ReplayError::StateComputationFailed {
+                    height,
+                    detail: format!("{:?}", err),
+                }))
+            }
+        }
+    }
+
+    // Writes the formatted message to the output.
+    fn output(&self, args: fmt::Arguments<'_>) {
+        let mut output = self.output.borrow_mut();
+        output
+            .write_fmt(args)
+            .and_then(|_| output.flush())
+            .expect("Couldn't write to the output");
+    }
+
+    /// Record every CUP encountered and every checkpoint hash computed into
+    /// the given writer. Each line contains the hash of the previous line, so
+    /// that the written log forms a tamper-evident hash chain.
+    pub fn with_audit_log(self, writer: Box<dyn Write + Send>) -> Self {
+        *self.audit_log.borrow_mut() = Some(AuditLog {
+            writer,
+            last_entry_hash: [0; 32],
+        });
+        self
+    }
+
+    // Appends the entry to the audit log, if one is configured.
+    fn audit(&self, entry: String) {
+        if let Some(audit_log) = self.audit_log.borrow_mut().as_mut() {
+            audit_log.append(entry);
+        }
+    }
+
+    /// If set, the signature of every certification is verified against the
+    /// public key of the subnet before it is redelivered to the state manager,
+    /// independently of the verifier the player was created with. An invalid
+    /// certification then fails the replay with `InvalidCertification`
+    /// instead of a panic inside the state manager.
+    pub fn with_strict_certification_redelivery(
+        mut self,
+        strict_certification_redelivery: bool,
+    ) -> Self {
+        self.strict_certification_redelivery = strict_certification_redelivery;
+        self
+    }
+
+    /// If set, a state divergence is downgraded to a warning and the replay
+    /// continues. All divergent heights are recorded in the divergence log.
+    pub fn with_tolerate_divergence(mut self, tolerate_divergence: bool) -> Self {
+        self.tolerate_divergence = tolerate_divergence;
+        self
+    }
+
+    /// Set a handler, which is consulted whenever the local state diverges
+    /// from the CUP and decides whether to continue, abort or roll back. The
+    /// handler takes precedence over the `tolerate_divergence` flag.
+    pub fn with_divergence_handler(self, handler: DivergenceHandler) -> Self {
+        *self.divergence_handler.borrow_mut() = Some(handler);
+        self
+    }
+
+    /// Set a callback, which is called with the height of the last delivered
+    /// batch and the best-known target height after each batch delivery of
+    /// `replay` and `restore`, e.g. to show the progress of a long restore.
+    pub fn with_progress_callback(mut self, callback: Box<dyn Fn(Height, Height) + Send>) -> Self {
+        self.progress_callback = Some(callback);
+        self
+    }
+
+    // Reports the progress of the batch delivery to the progress callback.
+    fn report_progress(&self, delivered_height: Height, target_height: Height) {
+        if let Some(callback) = &self.progress_callback {
+            callback(delivered_height, target_height);
+        }
+    }
+
+    /// Set a callback, which is called with the height and the hex-encoded
+    /// state hash as soon as a checkpoint is written during `replay` or
+    /// `restore`, e.g. to trigger a backup of the new checkpoint.
+    pub fn with_on_checkpoint(self, callback: Box<dyn FnMut(Height, &str) + Send>) -> Self {
+        *self.on_checkpoint.borrow_mut() = Some(callback);
+        self
+    }
+
+    /// Set an observer, which is called with the node id of the block maker
+    /// and the rank of every finalized block whose batch was delivered, e.g.
+    /// to spot a node making unusually many blocks. Blocks without a proposal
+    /// in the pool, such as the block of a CUP, are skipped.
+    pub fn with_block_metadata_observer(self, observer: BlockMetadataObserver) -> Self {
+        *self.block_metadata_observer.borrow_mut() = Some(observer);
+        self
+    }
+
+    /// Return all heights at which the local state diverged from the CUP,
+    /// together with the CUP's state hash and the local state hash.
+    pub fn divergence_log(&self) -> Vec<(Height, String, String)> {
+        self.divergence_log.borrow().clone()
+    }
+
+    /// Replay past finalized but un-executed blocks by delivering ingress
+    /// messages for execution, and make a full checkpoint of the latest
+    /// state when they all finish.
+    ///
+    /// It takes a function argument, which can be used to make extra ingress
+    /// messages for execution, which are delivered after the last finalized
+    /// block has been replayed. Note that this will advance the executed
+    /// batch height but not advance finalized block height in consensus
+    /// pool.
+    pub fn replay<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(&self, extra: F) -> ReplayResult {
+        self.replay_impl(extra, None)
+            .map(ReplayOutcome::into_params)
+    }
+
+    /// Like `replay`, but distinguishes a full replay from a replay which
+    /// detected an upgrade and has to be continued with another replica
+    /// version.
+    pub fn replay_detailed<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
+        &self,
+        extra: F,
+    ) -> Result<ReplayOutcome, ReplayError> {
+        self.replay_impl(extra, None)
+    }
+
+    /// Deliver only the batch with extra messages on top of the latest state,
+    /// e.g. to retry maintenance messages after a `replay` whose extra
+    /// messages failed, without replaying the finalized blocks again. Returns
+    /// the reply or the reject reason of each extra message.
+    pub fn deliver_extra_only<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
+        &self,
+        extra: F,
+    ) -> Result<Vec<(MessageId, Result<Vec<u8>, String>)>, ReplayError> {
+        let (_, extra_batch_delivery) =
+            self.deliver_extra_batch(self.message_routing(), self.consensus_pool.as_ref(), extra)?;
+        let (last_batch_height, msg_ids) = match extra_batch_delivery {
+            Some(delivery) => delivery,
+            None => return Ok(Vec::new()),
+        };
+        self.wait_for_state(last_batch_height)?;
+        if self.persists_final_checkpoint() {
+            self.state_manager.remove_states_below(last_batch_height);
+        }
+        let get_latest_status = self.ingress_history_reader.get_latest_status();
+        Ok(msg_ids
+            .into_iter()
+            .map(|msg_id| {
+                let result = match get_latest_status(&msg_id) {
+                    IngressStatus::Known {
+                        state: IngressState::Completed(WasmResult::Reply(bytes)),
+                        ..
+                    } => Ok(bytes),
+                    IngressStatus::Known {
+                        state: IngressState::Completed(WasmResult::Reject(message)),
+                        ..
+                    } => Err(message),
+                    IngressStatus::Known {
+                        state: IngressState::Failed(err),
+                        ..
+                    } => Err(err.to_string()),
+                    status => Err(format!("{:?}", status)),
+                };
+                (msg_id, result)
+            })
+            .collect())
+    }
+
+    /// Like `replay`, but additionally returns the non-fatal issues encountered
+    /// during the replay.
+    pub fn replay_with_report<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
+        &self,
+        extra: F,
+    ) -> Result<(StateParams, Vec<ReplayWarning>), ReplayError> {
+        self.warnings.borrow_mut().clear();
+        let params = self.replay_impl(extra, None)?.into_params();
+        Ok((params, self.warnings.take()))
+    }
+
+    /// Run `replay` on a blocking task and return a stream of its progress
+    /// events, ending with `ReplayEvent::Finished`. The player is consumed,
+    /// because it is moved to the blocking task. This has to be called from
+    /// within a tokio runtime.
+    #[cfg(feature = "stream")]
+    pub fn replay_stream<F>(mut self, extra: F) -> impl futures::Stream<Item = ReplayEvent>
+    where
+        F: FnMut(&Player, Time) -> Vec<SignedIngress> + Send + 'static,
+    {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let event_sender = sender.clone();
+        self.event_sink = Some(Box::new(move |event| {
+            // The receiver may have been dropped, in which case nobody is
+            // interested in the events anymore.
+            let _ = event_sender.unbounded_send(event);
+        }));
+        tokio::task::spawn_blocking(move || {
+            let result = self.replay(extra);
+            let _ = sender.unbounded_send(ReplayEvent::Finished(result));
+        });
+        receiver
+    }
+
+    /// Pauses the batch delivery. Batches which are being delivered already are
+    /// not interrupted, but no further batches are delivered until `resume` is
+    /// called. During a restore, this takes effect at the next height.
+    pub fn pause(&self) {
+        self.pause_handle.pause();
+    }
+
+    /// Resumes a paused batch delivery.
+    pub fn resume(&self) {
+        self.pause_handle.resume();
+    }
+
+    /// Return a handle which pauses and resumes the batch delivery of this
+    /// player from another thread.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause_handle.clone()
+    }
+
+    // Sends the given event to the event sink, if there is one.
+    fn emit(&self, event: ReplayEvent) {
+        if let Some(sink) = &self.event_sink {
+            sink(event);
+        }
+    }
+
+    // Records a non-fatal issue of the current replay.
+    fn warn(&self, warning: ReplayWarning) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Like `replay`, but stops as soon as the given wall-clock deadline has
+    /// passed. The deadline is checked before every delivered batch. If it was
+    /// exceeded, we wait until the last delivered batch is executed and
+    /// return `DeadlineExceeded` with the latest committed state.
+    pub fn replay_with_deadline<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
+        &self,
+        extra: F,
+        deadline: Instant,
+    ) -> ReplayResult {
+        self.replay_impl(extra, Some(deadline))
+            .map(ReplayOutcome::into_params)
+    }
+
+    fn replay_impl<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
+        &self,
+        extra: F,
+        deadline: Option<Instant>,
+    ) -> Result<ReplayOutcome, ReplayError> {
+        let mut required_version = None;
+        let has_consensus_pool = self.consensus_pool.is_some();
+        if !has_consensus_pool {
+            self.warn(ReplayWarning::NoConsensusPool);
+        }
+        if let (Some(consensus_pool), Some(_)) = (&self.consensus_pool, &self.certification_pool) {
+            match self.verify_latest_cup() {
+                Err(ReplayError::UpgradeDetected(_)) => {
+                    required_version = self.required_replica_version(&self.get_latest_cup()?.cup);
+                }
+                other => other?,
+            };
+            let pool_reader = &PoolReader::new(consensus_pool);
+            let finalized_height = pool_reader.get_finalized_height();
+            let mut target_height = finalized_height.min(
+                self.replay_target_height
+                    .map(Height::from)
+                    .unwrap_or_else(|| finalized_height),
+            );
+            if let Some(fraction) = self.replay_target_fraction {
+                let start_height = self
+                    .state_manager
+                    .latest_state_height()
+                    .min(finalized_height);
+                let range = finalized_height.get() - start_height.get();
+                let fraction_height = start_height + Height::from((range as f64 * fraction) as u64);
+                outputln!(
+                    self,
+                    "Replay target fraction {} resolved to height {}",
+                    fraction,
+                    fraction_height
+                );
+                target_height = target_height.min(fraction_height);
+            }
+            let target_height = Some(target_height);
+            let last_batch_height = match deadline {
+                None => {
+                    let last_batch_height =
+                        self.deliver_batches(self.message_routing(), pool_reader, target_height);
+                    self.report_progress(
+                        last_batch_height,
+                        target_height.unwrap_or(finalized_height),
+                    );
+                    last_batch_height
+                }
+                Some(deadline) => {
+                    let target_height = target_height.unwrap_or(finalized_height);
+                    let last_batch_height =
+                        self.deliver_batches_until_deadline(pool_reader, target_height, deadline);
+                    if last_batch_height < target_height {
+                        return Err(self.deadline_exceeded(last_batch_height));
+                    }
+                    last_batch_height
+                }
+            };
+            self.wait_for_state(last_batch_height)?;
+            // We only want to persist the checkpoint after the latest batch.
+            if !self.dry_run {
+                self.state_manager.remove_states_below(last_batch_height);
+            }
+
+            self.recertify()?;
+            outputln!(self, "All blocks successfully replayed.");
+
+            if self.final_manifest_check {
+                match self.check_final_manifest() {
+                    Ok(check) => {
+                        outputln!(
+                            self,
+                            "Manifest check at height {}: {} of {} chunks match",
+                            check.height,
+                            check.matching_chunks,
+                            check.total_chunks
+                        );
+                        if check.matches_cup && !check.differing_chunks.is_empty() {
+                            outputln!(
+                                self,
+                                "⚠️  The manifest hash matches the CUP, but the chunks {:?} differ",
+                                check.differing_chunks
+                            );
+                            self.warn(ReplayWarning::ManifestChunksDiffer {
+                                height: check.height,
+                                chunks: check.differing_chunks.clone(),
+                            });
+                        }
+                        *self.last_manifest_check.borrow_mut() = Some(check);
+                    }
+                    Err(err) => {
+                        outputln!(self, "Skipped the manifest check: {}", err);
+                        *self.last_manifest_check.borrow_mut() = None;
+                    }
+                }
+            }
+        }
+
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            let height = self.state_manager.latest_state_height();
+            return Err(self.deadline_exceeded(height));
+        }
+
+        let (latest_context_time, extra_batch_delivery) =
+            self.deliver_extra_batch(self.message_routing(), self.consensus_pool.as_ref(), extra)?;
+
+        if let Some((last_batch_height, msg_ids)) = extra_batch_delivery {
+            self.wait_for_state(last_batch_height)?;
+            if self.persists_final_checkpoint() {
+                // We only want to persist the checkpoint after the latest batch.
+                self.state_manager.remove_states_below(last_batch_height);
+            }
+
+            // check if the extra messages have been delivered successfully
+            let summary = self.summarize_extra_messages(msg_ids)?;
+            output!(self, "{}", summary);
+            if !summary.all_succeeded() {
+                return Err(ReplayError::ExtraMessagesFailed(summary));
+            }
+        }
+
+        // The registry version can only be queried on the NNS subnet, otherwise
+        // we use the latest version of the registry client instead.
+        let registry_version = if self.is_nns_subnet() {
+            self.get_latest_registry_version(latest_context_time)
+                .unwrap_or_else(|err| {
+                    self.warn(ReplayWarning::RegistryVersionFallback(err));
+                    self.registry.get_latest_version()
+                })
+        } else {
+            self.registry.get_latest_version()
+        };
+        outputln!(self, "Latest registry version: {}", registry_version);
+        let params = match self.latest_in_memory_state_params() {
+            Some(params) if !self.persists_final_checkpoint() => params,
+            _ => self.get_latest_state_height_and_hash()?,
+        };
+        Ok(match required_version {
+            Some(required_version) => ReplayOutcome::StoppedAtUpgrade {
+                params,
+                required_version,
+            },
+            None if !has_consensus_pool => ReplayOutcome::NoConsensusPool(params),
+            None => ReplayOutcome::FullyReplayed(params),
+        })
+    }
+
+    // Collects the execution outcomes of the given extra messages.
+    // If `abort_on_first_reject` is set, returns an error on the first message
+    // without a reply instead.
+    fn summarize_extra_messages(
+        &self,
+        msg_ids: Vec<MessageId>,
+    ) -> Result<ExtraMessagesSummary, ReplayError> {
+        let get_latest_status = self.ingress_history_reader.get_latest_status();
+        let mut summary = ExtraMessagesSummary::default();
+        let mut results = Vec::new();
+        for msg_id in msg_ids {
+            let rejection = match get_latest_status(&msg_id) {
+                IngressStatus::Known {
+                    state: IngressState::Completed(WasmResult::Reply(bytes)),
+                    ..
+                } => {
+                    outputln!(
+                        self,
+                        "Ingress id={} response={}",
+                        &msg_id,
+                        hex::encode(&bytes)
+                    );
+                    results.push(serde_json::json!({
+                        "message_id": msg_id.to_string(),
+                        "status": "replied",
+                        "reply_hex": hex::encode(bytes),
+                    }));
+                    summary.succeeded += 1;
+                    None
+                }
+                IngressStatus::Known {
+                    state: IngressState::Completed(WasmResult::Reject(message)),
+                    ..
+                } => {
+                    results.push(serde_json::json!({
+                        "message_id": msg_id.to_string(),
+                        "status": "rejected",
+                        "reject_code": RejectCode::CanisterReject as u64,
+                        "reject_message": message,
+                    }));
+                    summary.add_reject(RejectCode::CanisterReject, message.clone());
+                    Some(message)
+                }
+                IngressStatus::Known {
+                    state: IngressState::Failed(err),
+                    ..
+                } => {
+                    results.push(serde_json::json!({
+                        "message_id": msg_id.to_string(),
+                        "status": "rejected",
+                        "reject_code": err.reject_code() as u64,
+                        "reject_message": err.to_string(),
+                    }));
+                    summary.add_reject(err.reject_code(), err.to_string());
+                    Some(err.to_string())
+                }
+                status => {
+                    let reason = format!("{:?}", status);
+                    results.push(serde_json::json!({
+                        "message_id": msg_id.to_string(),
+                        "status": "unfinished",
+                        "reject_message": reason,
+                    }));
+                    summary.unfinished.push((msg_id.clone(), reason.clone()));
+                    Some(reason)
+                }
+            };
+            if let Some(reason) = rejection {
+                if self.abort_on_first_reject {
+                    self.write_extra_results(results);
+                    return Err(ReplayError::ExtraMessageRejected {
+                        message_id: msg_id,
+                        reason,
+                    });
+                }
+            }
+        }
+        self.write_extra_results(results);
+        Ok(summary)
+    }
+
+    /// Redeliver all certifications from the certification pool to the state
+    /// manager without delivering any batches, and return the certified
+    /// heights. The state manager will panic if there is any mismatch.
+    pub fn recertify(&self) -> Result<Vec<Height>, ReplayError> {
+        let certification_pool = match &self.certification_pool {
+            Some(pool) => pool,
+            None => return Ok(Vec::new()),
+        };
+        let crypto = self.strict_certification_redelivery.then(|| {
+            ic_crypto::CryptoComponentFatClient::new_for_verification_only(self.registry.clone())
+        });
+        let mut heights = Vec::new();
+        output!(self, "Redelivering certifications:");
+        for h in certification_pool.certified_heights() {
+            let certification = certification_pool
+                .certification_at_height(h)
+                .unwrap_or_else(|| panic!("Missing certification at height {:?}", h));
+            if let Some(crypto) = &crypto {
+                let registry_version = self
+                    .consensus_pool
+                    .as_ref()
+                    .and_then(|pool| PoolReader::new(pool).registry_version(h))
+                    .unwrap_or_else(|| self.registry.get_latest_version());
+                crypto
+                    .verify_combined_threshold_sig_by_public_key(
+                        &certification.signed.signature.signature,
+                        &certification.signed.content,
+                        self.subnet_id,
+                        registry_version,
+                    )
+                    .map_err(|_| ReplayError::InvalidCertification(h))?;
+            }
+            self.state_manager
+                .deliver_state_certification(certification);
+            output!(self, " {}", h);
+            heights.push(h);
+        }
+        outputln!(self);
+        heights.sort();
+        Ok(heights)
+    }
+
+    // Delivers finalized batches one by one up to the target height, as long as
+    // the deadline has not passed. Returns the height of the last delivered batch.
+    fn deliver_batches_until_deadline(
+        &self,
+        pool: &PoolReader<'_>,
+        target_height: Height,
+        deadline: Instant,
+    ) -> Height {
+        let mut last_batch_height = self.message_routing().expected_batch_height().decrement();
+        while last_batch_height < target_height && Instant::now() < deadline {
+            last_batch_height = self.deliver_batches(
+                self.message_routing(),
+                pool,
+                Some(last_batch_height.increment()),
+            );
+            self.report_progress(last_batch_height, target_height);
+        }
+        last_batch_height
+    }
+
+    // Waits until the state at the last delivered height is committed and
+    // returns the error reporting the exceeded deadline.
+    fn deadline_exceeded(&self, last_batch_height: Height) -> ReplayError {
+        if let Err(err) = self.wait_for_state(last_batch_height) {
+            return err;
+        }
+        outputln!(
+            self,
+            "Deadline exceeded after delivering batches up to the height {}",
+            last_batch_height
+        );
+        match self.get_latest_state_height_and_hash() {
+            Ok(params) => ReplayError::DeadlineExceeded(params),
+            Err(err) => err,
+        }
+    }
+
+    // Blocks until the state at the given height or a later one is committed,
+    // without waiting for the remaining delivered batches.
+    fn wait_for_executed_height(&self, height: Height) {
+        while self.executed_height() < height {
+            std::thread::sleep(WAIT_DURATION);
+        }
+    }
+
+    // Blocks until the state at the given height is committed. Returns an error
+    // if the latest state height differs from the given height afterwards, in
+    // which case no checkpoint is reported.
+    fn wait_for_state(&self, height: Height) -> Result<(), ReplayError> {
+        // No states are produced in consensus only mode.
+        if self.no_op_message_routing.is_some() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let mut iterations: u64 = 0;
+        loop {
+            iterations += 1;
+            if iterations % WAIT_PROGRESS_INTERVAL == 0 {
+                info!(
+                    self.log,
+                    "state_progress target_height={} latest_state_height={} elapsed_secs={}",
+                    height,
+                    self.state_manager.latest_state_height(),
+                    start.elapsed().as_secs()
+                );
+            }
+            // We first wait until `height` was executed. Otherwise the state
+            // manager would return a permanent error on a too big height.
+            if self.state_manager.latest_state_height() >= height {
+                break;
+            }
+            std::thread::sleep(WAIT_DURATION);
+        }
+        outputln!(
+            self,
+            "Latest state height is {}",
+            self.state_manager.latest_state_height()
+        );
+        if height != self.state_manager.latest_state_height() {
+            let diagnostics = self.batch_height_diagnostics();
+            outputln!(
+                self,
+                "Latest delivered batch is of height {} while the latest known state is at height {}: {:?}",
+                height,
+                diagnostics.latest_state,
+                diagnostics
+            );
+            if diagnostics.expected_batch != height.increment() {
+                return Err(ReplayError::BatchHeightMismatch {
+                    delivered: height,
+                    diagnostics,
+                });
+            }
+            return Err(ReplayError::StateHeightMismatch {
+                expected: height,
+                actual: diagnostics.latest_state,
+            });
+        }
+        if let Some(hash) = self.get_state_hash(height)? {
+            outputln!(self, "Latest checkpoint at height: {}", height);
+            outputln!(self, "Latest state hash: {}", hex::encode(&hash.get().0));
+            self.audit(format!(
+                "checkpoint height={} state_hash={}",
+                height,
+                hex::encode(&hash.get().0)
+            ));
+            self.emit(ReplayEvent::CheckpointWritten((
+                height,
+                hex::encode(&hash.get().0),
+            )));
+            if let Some(callback) = self.on_checkpoint.borrow_mut().as_mut() {
+                callback(height, &hex::encode(&hash.get().0));
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the heights of the message routing, the state manager and the
+    /// consensus pool, e.g. to diagnose a mismatch between them.
+    pub fn batch_height_diagnostics(&self) -> BatchHeightDiagnostics {
+        BatchHeightDiagnostics {
+            expected_batch: self.message_routing().expected_batch_height(),
+            latest_state: self.state_manager.latest_state_height(),
+            latest_certified: self.state_manager.latest_certified_height(),
+            finalized: self
+                .consensus_pool
+                .as_ref()
+                .map(|pool| PoolReader::new(pool).get_finalized_height()),
+        }
+    }
+
+    /// Blocks until the given height is certified or the optional timeout
+    /// expires.
+    pub fn wait_for_certification(
+        &self,
+        height: Height,
+        timeout: Option<Duration>,
+    ) -> Result<(), ReplayError> {
+        let start = std::time::Instant::now();
+        while self.state_manager.latest_certified_height() < height {
+            if timeout.map_or(false, |timeout| start.elapsed() >= timeout) {
+                outputln!(self, "Height {} was not certified in time", height);
+                return Err(ReplayError::CertificationTimeout(height));
+            }
+            std::thread::sleep(WAIT_DURATION);
+        }
+        outputln!(
+            self,
+            "Latest certified height is {}",
+            self.state_manager.latest_certified_height()
+        );
+        Ok(())
     }
 
     /// Return latest height and state hash according to state manager (latest checkpoint or CUP
     /// state).
-    pub fn get_latest_state_height_and_hash(&self) -> StateParams {
+    pub fn get_latest_state_height_and_hash(&self) -> ReplayResult {
         let (height, hash_raw) = {
             let height = self.state_manager.latest_state_height();
-            self.wait_for_state(height);
-            if let Ok(hash_raw) = self.state_manager.get_state_hash_at(height) {
+            self.wait_for_state(height)?;
+            if self.no_op_message_routing.is_some() {
+                let last_cup = self.get_latest_cup()?.cup;
+                (last_cup.height(), last_cup.content.state_hash)
+            } else if let Ok(hash_raw) = self.state_manager.get_state_hash_at(height) {
                 (height, hash_raw)
             } else {
                 // If the latest state height corresponds to an in-memory state only, we return the
                 // state hash of the latest CUP
-                let last_cup = self.get_latest_cup().cup;
+                let last_cup = self.get_latest_cup()?.cup;
                 (last_cup.height(), last_cup.content.state_hash)
             }
         };
         let hash = hex::encode(&hash_raw.get().0);
-        (height, hash)
+        Ok((height, hash))
+    }
+
+    /// Like `get_latest_state_height_and_hash`, but returns the result as a
+    /// JSON object of the form `{"height": <u64>, "state_hash": "<hex>"}`.
+    pub fn state_params_json(&self) -> Result<String, ReplayError> {
+        let params = self.get_latest_state_height_and_hash()?;
+        Ok(serde_json::to_string(&StateParamsJson::from(&params))
+            .expect("Couldn't serialize the state params"))
+    }
+
+    /// Return the state hashes at all given heights in the order in which
+    /// they become available. All pending heights are polled in the same
+    /// round, so the total wait is bounded by the slowest hash computation
+    /// rather than the sum of them. Non-checkpoint heights map to `None`, and
+    /// heights the state manager can't provide a hash for, e.g. because they
+    /// were never committed or already removed, to an error.
+    pub fn state_hashes_at(
+        &self,
+        heights: &[Height],
+    ) -> Vec<(Height, Result<Option<String>, ReplayError>)> {
+        let mut pending = heights.to_vec();
+        let mut results = Vec::with_capacity(heights.len());
+        loop {
+            pending.retain(|height| match self.poll_state_hash(*height) {
+                Poll::Ready(hash) => {
+                    let hash = hash.map(|hash| hash.map(|hash| hex::encode(&hash.get().0)));
+                    results.push((*height, hash));
+                    false
+                }
+                Poll::Pending => true,
+            });
+            if pending.is_empty() {
+                return results;
+            }
+            std::thread::sleep(WAIT_DURATION);
+        }
+    }
+
+    /// Return the number of states the state manager currently holds in memory.
+    pub fn in_memory_state_count(&self) -> usize {
+        self.state_manager.resident_state_count()
+    }
+
+    /// Return the heights of all checkpoints available on disk.
+    pub fn checkpoint_heights(&self) -> Vec<Height> {
+        self.state_manager
+            .state_layout()
+            .checkpoint_heights()
+            .unwrap_or_else(|err| panic!("Couldn't list the checkpoint heights: {:?}", err))
+    }
+
+    /// Return the manifest of the checkpoint at the given height.
+    pub fn export_manifest(&self, height: Height) -> Result<Manifest, String> {
+        let cp_layout = self
+            .state_manager
+            .state_layout()
+            .checkpoint(height)
+            .map_err(|err| {
+                format!(
+                    "Failed to open the checkpoint at height {}: {}",
+                    height, err
+                )
+            })?;
+        compute_checkpoint_manifest(&cp_layout, &self.log)
+    }
+
+    /// Recompute the manifest of the checkpoint at the given height from disk
+    /// and return whether its hash matches the state hash recorded by the
+    /// state manager, e.g. to detect a corrupted checkpoint.
+    pub fn verify_checkpoint_integrity(&self, height: Height) -> Result<bool, String> {
+        if !self.checkpoint_heights().contains(&height) {
+            return Err(format!("There is no checkpoint at height {}", height));
+        }
+        let state_hash = self
+            .state_manager
+            .get_state_hash_at(height)
+            .map_err(|err| format!("No state hash at height {}: {:?}", height, err))?;
+        let manifest = self.export_manifest(height)?;
+        Ok(manifest_hash(&manifest).to_vec() == state_hash.get_ref().0)
+    }
+
+    /// Compare the state hashes at the heights of the given reference, e.g. a
+    /// golden file from a known-good replay, with the hashes of the local
+    /// states. If the state manager doesn't know the hash of a height anymore,
+    /// it is recomputed from the checkpoint on disk. Returns all heights whose
+    /// hashes differ, so an empty result means that the replay reproduced the
+    /// reference exactly.
+    pub fn compare_against_reference(&self, reference: &[(Height, String)]) -> Vec<HashMismatch> {
+        let checkpoint_heights = self.checkpoint_heights();
+        reference
+            .iter()
+            .filter_map(|(height, expected)| {
+                let actual = self.hex_state_hash(*height, &checkpoint_heights);
+                if actual.as_ref() == Some(expected) {
+                    return None;
+                }
+                Some(HashMismatch {
+                    height: *height,
+                    expected: expected.clone(),
+                    actual,
+                })
+            })
+            .collect()
+    }
+
+    /// Return the state hashes of all checkpoints in ascending height order,
+    /// i.e. the sequence of states the replay progressed through. Checkpoints
+    /// whose hash can't be determined are skipped.
+    pub fn state_hash_chain(&self) -> Vec<(Height, String)> {
+        let mut checkpoint_heights = self.checkpoint_heights();
+        checkpoint_heights.sort();
+        checkpoint_heights
+            .iter()
+            .filter_map(|height| {
+                self.hex_state_hash(*height, &checkpoint_heights)
+                    .map(|hash| (*height, hash))
+            })
+            .collect()
+    }
+
+    // Returns the hex-encoded state hash at the given height, falling back to
+    // the hash of the manifest on disk for checkpoints the state manager
+    // doesn't know the hash of, e.g. those loaded at startup.
+    fn hex_state_hash(&self, height: Height, checkpoint_heights: &[Height]) -> Option<String> {
+        match self.state_manager.get_state_hash_at(height) {
+            Ok(hash) => Some(hex::encode(&hash.get().0)),
+            Err(_) if checkpoint_heights.contains(&height) => self
+                .export_manifest(height)
+                .ok()
+                .map(|manifest| hex::encode(manifest_hash(&manifest))),
+            Err(_) => None,
+        }
+    }
+
+    /// Copy the checkpoint at the given height to `dest` and verify that the
+    /// manifest of the copy matches the state hash of the checkpoint.
+    pub fn snapshot_state(&self, height: Height, dest: &Path) -> Result<(), String> {
+        if !self.checkpoint_heights().contains(&height) {
+            return Err(format!("There is no checkpoint at height {}", height));
+        }
+        let state_hash = self
+            .state_manager
+            .get_state_hash_at(height)
+            .map_err(|err| format!("No state hash at height {}: {:?}", height, err))?;
+        let cp_layout = self
+            .state_manager
+            .state_layout()
+            .checkpoint(height)
+            .map_err(|err| {
+                format!(
+                    "Failed to open the checkpoint at height {}: {}",
+                    height, err
+                )
+            })?;
+        outputln!(
+            self,
+            "Copying the checkpoint at height {} to {:?}...",
+            height,
+            dest
+        );
+        copy_dir_recursively(cp_layout.raw_path(), dest).map_err(|err| {
+            format!(
+                "Couldn't copy the checkpoint at height {} to {:?}: {:?}",
+                height, dest, err
+            )
+        })?;
+
+        let dest_layout = CheckpointLayout::<ReadOnly>::new(dest.to_path_buf(), height)
+            .map_err(|err| format!("Failed to create checkpoint layout: {}", err))?;
+        let manifest = compute_checkpoint_manifest(&dest_layout, &self.log)?;
+        if manifest_hash(&manifest).to_vec() != state_hash.get_ref().0 {
+            return Err(format!(
+                "The manifest hash of the copied checkpoint {} differs from the state hash {}",
+                hex::encode(manifest_hash(&manifest)),
+                hex::encode(&state_hash.get_ref().0)
+            ));
+        }
+        outputln!(
+            self,
+            "Copied the checkpoint at height {} to {:?}",
+            height,
+            dest
+        );
+        Ok(())
     }
 
     /// Fetch registry records from the given `nns_url`, and update the local
@@ -462,16 +2442,33 @@ impl Player {
     pub fn update_registry_local_store(&self) {
         let local_store_path = self.local_store_path.clone().expect(
            "update_registry_local_store can only be used with registry configured with local store");
-        println!("RegistryLocalStore path: {:?}", local_store_path);
+        outputln!(self, "RegistryLocalStore path: {:?}", local_store_path);
         let latest_version = self.registry.get_latest_version();
-        println!("RegistryLocalStore latest version: {}", latest_version);
+        outputln!(
+            self,
+            "RegistryLocalStore latest version: {}",
+            latest_version
+        );
         let records = self
-            .get_changes_since(
-                latest_version.get(),
-                current_time() + Duration::from_secs(60),
-            )
+            .get_changes_since_now(latest_version.get())
             .unwrap_or_else(|err| panic!("Error in get_certified_changes_since: {}", err));
-        write_records_to_local_store(&local_store_path, latest_version, records)
+        let mut output = self.output.borrow_mut();
+        write_records_to_local_store(&local_store_path, latest_version, records, &mut **output)
+    }
+
+    /// Return the latest registry version found in the registry local store,
+    /// i.e. the highest version of its records. Only the changelog entries
+    /// above the version of the registry client are read.
+    pub fn local_store_latest_version(&self) -> Result<RegistryVersion, String> {
+        let local_store_path = self
+            .local_store_path
+            .as_ref()
+            .ok_or("The registry is not configured with a local store")?;
+        let known_version = self.registry.get_latest_version();
+        let records = LocalStoreImpl::new(local_store_path)
+            .get_updates_since(known_version)
+            .map_err(|err| format!("Couldn't read the registry local store: {:?}", err))?;
+        Ok(latest_record_version(known_version, &records))
     }
 
     /// Deliver finalized batches since last expected batch height.
@@ -481,8 +2478,41 @@ impl Player {
         pool: &PoolReader<'_>,
         replay_target_height: Option<Height>,
     ) -> Height {
+        let message_routing: &dyn MessageRouting = &TracingMessageRouting {
+            message_routing,
+            execution_trace: &self.execution_trace,
+        };
+        let checkpointless_routing;
+        let message_routing = if self.skip_intermediate_checkpoints || self.dry_run {
+            checkpointless_routing = CheckpointlessMessageRouting {
+                message_routing,
+                checkpoint_height: self.final_checkpoint_height.filter(|_| !self.dry_run),
+            };
+            &checkpointless_routing as &dyn MessageRouting
+        } else {
+            message_routing
+        };
+        let allowlist_routing;
+        let message_routing = match &self.canister_allowlist {
+            Some(allowlist) => {
+                allowlist_routing = AllowlistMessageRouting {
+                    message_routing,
+                    allowlist,
+                };
+                &allowlist_routing as &dyn MessageRouting
+            }
+            None => message_routing,
+        };
         let expected_batch_height = message_routing.expected_batch_height();
+        let replay_target_height =
+            self.apply_replay_target_time(pool, expected_batch_height, replay_target_height);
+        let mut retries = 0;
         let last_batch_height = loop {
+            if self.pause_handle.is_paused() {
+                outputln!(self, "Batch delivery paused");
+                self.pause_handle.wait_while_paused();
+                outputln!(self, "Batch delivery resumed");
+            }
             match deliver_batches(
                 message_routing,
                 pool,
@@ -495,27 +2525,154 @@ impl Player {
                 None,
             ) {
                 Ok(h) => break h,
-                Err(MessageRoutingError::QueueIsFull) => std::thread::sleep(WAIT_DURATION),
+                Err(MessageRoutingError::QueueIsFull) => {
+                    retries += 1;
+                    std::thread::sleep(WAIT_DURATION)
+                }
                 Err(MessageRoutingError::Ignored { .. }) => {
                     unreachable!();
                 }
             }
         };
-        println!(
+        if retries > 0 {
+            self.warn(ReplayWarning::QueueFull {
+                height: last_batch_height,
+                retries,
+            });
+        }
+        if last_batch_height >= expected_batch_height {
+            let block_time = |height| pool.get_finalized_block(height).map(|b| b.context.time);
+            if let (Some(first), Some(last)) = (
+                block_time(expected_batch_height),
+                block_time(last_batch_height),
+            ) {
+                let mut range = self.replayed_time_range.borrow_mut();
+                *range = Some(range.map_or((first, last), |(start, _)| (start, last)));
+            }
+        }
+        if let Some(observer) = self.block_metadata_observer.borrow_mut().as_mut() {
+            let mut height = expected_batch_height;
+            while height <= last_batch_height {
+                if let Some(block) = pool.get_finalized_block(height) {
+                    let proposal = pool
+                        .pool()
+                        .validated()
+                        .block_proposal()
+                        .get_by_height(height)
+                        .find(|proposal| proposal.content.as_ref() == &block);
+                    if let Some(proposal) = proposal {
+                        observer(height, proposal.signature.signer, block.rank);
+                    }
+                }
+                height = height.increment();
+            }
+        }
+        outputln!(
+            self,
             "latest_batch_height = {}, batches = {}",
             last_batch_height,
             last_batch_height - expected_batch_height.decrement()
         );
-        println!("Delivered batches up to the height {}", last_batch_height);
+        outputln!(
+            self,
+            "Delivered batches up to the height {}",
+            last_batch_height
+        );
+        if last_batch_height >= expected_batch_height {
+            self.emit(ReplayEvent::BatchDelivered(last_batch_height));
+        }
         last_batch_height
     }
 
+    // Lowers the given target height to the height preceding the first
+    // finalized block from `start_height` on whose time exceeds the replay
+    // target time.
+    fn apply_replay_target_time(
+        &self,
+        pool: &PoolReader<'_>,
+        start_height: Height,
+        target_height: Option<Height>,
+    ) -> Option<Height> {
+        let target_time = match self.replay_target_time {
+            Some(time) => time,
+            None => return target_height,
+        };
+        let finalized_height = pool.get_finalized_height();
+        let last_height = target_height.map_or(finalized_height, |h| h.min(finalized_height));
+        let mut height = start_height;
+        while height <= last_height {
+            match pool.get_finalized_block(height) {
+                Some(block) if block.context.time > target_time => return Some(height.decrement()),
+                Some(_) => height = height.increment(),
+                None => break,
+            }
+        }
+        target_height
+    }
+
+    // Returns true if the block following the given height is known to be
+    // later than the replay target time.
+    fn replay_target_time_reached(&self, pool: &PoolReader<'_>, height: Height) -> bool {
+        match (
+            self.replay_target_time,
+            pool.get_finalized_block(height.increment()),
+        ) {
+            (Some(target_time), Some(block)) => block.context.time > target_time,
+            _ => false,
+        }
+    }
+
     fn deliver_extra_batch<F: FnMut(&Player, Time) -> Vec<SignedIngress>>(
         &self,
         message_routing: &dyn MessageRouting,
         pool: Option<&ConsensusPoolImpl>,
         mut extra: F,
-    ) -> (Time, Option<(Height, Vec<MessageId>)>) {
+    ) -> Result<(Time, Option<(Height, Vec<MessageId>)>), ReplayError> {
+        let mut extra_batch = self.new_extra_batch(message_routing, pool);
+        if let Some(time) = self.extra_batch_time {
+            let earliest = self.state_manager.get_latest_state().take().time();
+            if time < earliest {
+                return Err(ReplayError::InvalidExtraBatchTime {
+                    requested: time,
+                    earliest,
+                });
+            }
+            extra_batch.time = time;
+        }
+        let context_time = extra_batch.time;
+        let extra_msgs = extra(self, context_time);
+        if extra_msgs.is_empty() {
+            return Ok((context_time, None));
+        }
+        let extra_msg_ids = extra_msgs.iter().map(|msg| msg.id()).collect::<Vec<_>>();
+        if !extra_msgs.is_empty() {
+            extra_batch.payload.ingress = IngressPayload::from(extra_msgs);
+            outputln!(self, "extra_batch created with new ingress");
+        }
+        extra_batch.requires_full_state_hash = self.persists_final_checkpoint();
+        let batch_number = extra_batch.batch_number;
+        self.deliver_batch_with_retries(message_routing, extra_batch);
+        Ok((context_time, Some((batch_number, extra_msg_ids))))
+    }
+
+    // Returns the latest state height and the hash of its partial state, if the
+    // latest state is not certified yet.
+    fn latest_in_memory_state_params(&self) -> Option<StateParams> {
+        let height = self.state_manager.latest_state_height();
+        self.state_manager
+            .list_state_hashes_to_certify()
+            .into_iter()
+            .find(|(h, _)| *h == height)
+            .map(|(height, hash)| (height, hex::encode(&hash.get().0)))
+    }
+
+    // Creates an empty batch at the next expected height, following the last
+    // finalized block if there is a consensus pool.
+    fn new_extra_batch(
+        &self,
+        message_routing: &dyn MessageRouting,
+        pool: Option<&ConsensusPoolImpl>,
+    ) -> Batch {
         let (registry_version, time, randomness) = match pool {
             None => (
                 self.registry.get_latest_version(),
@@ -534,16 +2691,20 @@ impl Player {
                         )
                     });
 
+                let time_bump = if self.exact_extra_batch_time {
+                    Duration::from_nanos(0)
+                } else {
+                    Duration::from_nanos(1)
+                };
                 (
                     last_block.context.registry_version,
-                    last_block.context.time + Duration::from_nanos(1),
+                    last_block.context.time + time_bump,
                     Randomness::from(crypto_hashable_to_seed(&last_block)),
                 )
             }
         };
-        let batch_number = message_routing.expected_batch_height();
-        let mut extra_batch = Batch {
-            batch_number,
+        Batch {
+            batch_number: message_routing.expected_batch_height(),
             requires_full_state_hash: true,
             payload: BatchPayload::default(),
             // Use a fake randomness here since we don't have random tape for extra messages
@@ -552,34 +2713,100 @@ impl Player {
             registry_version,
             time,
             consensus_responses: Vec::new(),
-        };
-        let context_time = extra_batch.time;
-        let extra_msgs = extra(self, context_time);
-        if extra_msgs.is_empty() {
-            return (context_time, None);
         }
-        let extra_msg_ids = extra_msgs.iter().map(|msg| msg.id()).collect::<Vec<_>>();
-        if !extra_msgs.is_empty() {
-            extra_batch.payload.ingress = IngressPayload::from(extra_msgs);
-            println!("extra_batch created with new ingress");
-        }
-        let batch_number = extra_batch.batch_number;
+    }
+
+    // Delivers the given batch, retrying as long as the message routing queue
+    // is full.
+    fn deliver_batch_with_retries(&self, message_routing: &dyn MessageRouting, batch: Batch) {
+        let message_routing = TracingMessageRouting {
+            message_routing,
+            execution_trace: &self.execution_trace,
+        };
+        let batch_number = batch.batch_number;
+        let mut retries = 0;
         loop {
-            match message_routing.deliver_batch(extra_batch.clone()) {
+            match message_routing.deliver_batch(batch.clone()) {
                 Ok(()) => {
-                    println!("Delivered batch {}", batch_number);
+                    outputln!(self, "Delivered batch {}", batch_number);
+                    self.emit(ReplayEvent::BatchDelivered(batch_number));
                     break;
                 }
-                Err(MessageRoutingError::QueueIsFull) => std::thread::sleep(WAIT_DURATION),
+                Err(MessageRoutingError::QueueIsFull) => {
+                    retries += 1;
+                    std::thread::sleep(WAIT_DURATION)
+                }
                 Err(MessageRoutingError::Ignored { .. }) => {
                     unreachable!("Unexpected error on a valid batch number {}", batch_number);
                 }
             }
         }
-        (
-            context_time,
-            Some((extra_batch.batch_number, extra_msg_ids)),
-        )
+        if retries > 0 {
+            self.warn(ReplayWarning::QueueFull {
+                height: batch_number,
+                retries,
+            });
+        }
+    }
+
+    /// Deliver an empty batch requiring a full state hash at the next height,
+    /// so that a checkpoint is created at this height, and return its height
+    /// and state hash. In a dry run, the older states are kept.
+    pub fn checkpoint_now(&self) -> Result<StateParams, String> {
+        let mut batch = self.new_extra_batch(self.message_routing(), self.consensus_pool.as_ref());
+        // The batch time must not lie before the time of the latest state, which
+        // may stem from a previous extra batch.
+        let latest_state_time = self.state_manager.get_latest_state().take().time();
+        batch.time = batch.time.max(latest_state_time + Duration::from_nanos(1));
+        let height = batch.batch_number;
+        self.deliver_batch_with_retries(self.message_routing(), batch);
+        self.wait_for_state(height)
+            .map_err(|err| format!("Checkpointing failed: {:?}", err))?;
+        // We only want to persist the checkpoint after the latest batch.
+        if !self.dry_run {
+            self.state_manager.remove_states_below(height);
+        }
+        let hash = self
+            .get_state_hash(height)
+            .map_err(|err| format!("Checkpointing failed: {:?}", err))?
+            .ok_or_else(|| format!("No state hash found at height {}", height))?;
+        Ok((height, hex::encode(&hash.get().0)))
+    }
+
+    /// Return the range of ingress expiry times usable for queries against the
+    /// latest replayed state: from the state's batch time up to the maximum
+    /// ingress expiry window. Since a replayed state can be arbitrarily old,
+    /// the window is counted from the later of the batch time and the current
+    /// time. The window is widened on both ends by the configured clock skew.
+    pub fn ingress_expiry_window(&self) -> (Time, Time) {
+        let batch_time = self.state_manager.get_latest_state().take().time();
+        let skew = self.ingress_expiry_clock_skew;
+        let min_expiry = Time::from_nanos_since_unix_epoch(
+            batch_time
+                .as_nanos_since_unix_epoch()
+                .saturating_sub(skew.as_nanos() as u64),
+        );
+        let max_expiry = batch_time.max(current_time()) + MAX_INGRESS_TTL + PERMITTED_DRIFT + skew;
+        (min_expiry, max_expiry)
+    }
+
+    /// Check that the given ingress expiry lies within the ingress expiry
+    /// window and return a descriptive error otherwise.
+    pub fn validate_ingress_expiry(&self, expiry: Time) -> Result<(), String> {
+        let (min_expiry, max_expiry) = self.ingress_expiry_window();
+        if expiry < min_expiry {
+            return Err(format!(
+                "Ingress expiry {} is too old: it lies before {}, the batch time of the latest state minus the tolerated clock skew",
+                expiry, min_expiry
+            ));
+        }
+        if expiry > max_expiry {
+            return Err(format!(
+                "Ingress expiry {} is too far in the future: it lies after {}",
+                expiry, max_expiry
+            ));
+        }
+        Ok(())
     }
 
     /// Return latest BlessedReplicaVersions record by querying the registry
@@ -589,68 +2816,202 @@ impl Player {
         ingress_expiry: Time,
     ) -> Result<BlessedReplicaVersions, String> {
         let key = make_blessed_replica_version_key();
+        let payload = serialize_get_value_request(key.as_bytes().to_vec(), None)
+            .map_err(|err| format!("{}", err))?;
+        match self.query_canister(REGISTRY_CANISTER_ID, "get_value", payload, ingress_expiry)? {
+            WasmResult::Reply(v) => {
+                let bytes = deserialize_get_value_response(v)
+                    .map_err(|err| format!("{}", err))?
+                    .0;
+                let record = deserialize_registry_value::<BlessedReplicaVersions>(Ok(Some(bytes)))
+                    .map_err(|err| format!("{}", err))?
+                    .expect("BlessedReplicaVersions does not exist");
+                Ok(record)
+            }
+            WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
+        }
+    }
+
+    /// Like `get_blessed_replica_versions`, using the default ingress expiry.
+    pub fn get_blessed_replica_versions_now(&self) -> Result<BlessedReplicaVersions, String> {
+        self.get_blessed_replica_versions(self.default_ingress_expiry())
+    }
+
+    /// Return the values of the given registry keys at the latest version by
+    /// querying the registry canister. The queries run in parallel against
+    /// the same snapshot of the latest state, with at most the configured
+    /// number of queries at a time. Keys which are not present in the
+    /// registry map to `None`. Fails if the default ingress expiry lies
+    /// outside the ingress expiry window of the latest state.
+    pub fn registry_records(
+        &self,
+        keys: &[String],
+    ) -> Result<Vec<(String, Result<Option<Vec<u8>>, String>)>, String> {
+        let ingress_expiry = self.default_ingress_expiry();
+        self.validate_ingress_expiry(ingress_expiry)?;
+        let state = self.state_manager.get_latest_state().take();
+        let query_handler = &self.http_query_handler;
+        let mut results: Vec<_> = keys.iter().map(|key| (key.clone(), Ok(None))).collect();
+        let mut thread_pool = scoped_threadpool::Pool::new(self.max_parallel_queries);
+        thread_pool.scoped(|scope| {
+            for (key, result) in results.iter_mut() {
+                let state = Arc::clone(&state);
+                scope.execute(move || {
+                    *result =
+                        query_registry_value(query_handler.as_ref(), state, key, ingress_expiry);
+                });
+            }
+        });
+        Ok(results)
+    }
+
+    /// Query the given method of the given canister on the latest state as the
+    /// anonymous user and return the raw result.
+    pub fn query_canister(
+        &self,
+        canister_id: CanisterId,
+        method: &str,
+        payload: Vec<u8>,
+        ingress_expiry: Time,
+    ) -> Result<WasmResult, String> {
+        self.query_canister_as(
+            PrincipalId::new_anonymous(),
+            canister_id,
+            method,
+            payload,
+            ingress_expiry,
+        )
+    }
+
+    /// Like `query_canister`, but sends the query as the given principal.
+    pub fn query_canister_as(
+        &self,
+        sender: PrincipalId,
+        canister_id: CanisterId,
+        method: &str,
+        payload: Vec<u8>,
+        ingress_expiry: Time,
+    ) -> Result<WasmResult, String> {
+        self.validate_ingress_expiry(ingress_expiry)?;
         let query = UserQuery {
-            source: UserId::from(PrincipalId::new_anonymous()),
-            receiver: REGISTRY_CANISTER_ID,
-            method_name: "get_value".to_string(),
-            method_payload: serialize_get_value_request(key.as_bytes().to_vec(), None)
-                .map_err(|err| format!("{}", err))?,
+            source: UserId::from(sender),
+            receiver: canister_id,
+            method_name: method.to_string(),
+            method_payload: payload,
             ingress_expiry: ingress_expiry.as_nanos_since_unix_epoch(),
             nonce: None,
         };
-        match self.http_query_handler.query(
-            query,
-            self.state_manager.get_latest_state().take(),
+        self.http_query_handler
+            .query(
+                query,
+                self.state_manager.get_latest_state().take(),
+                Vec::new(),
+            )
+            .map_err(|err| format!("Query failed: {:?}", err))
+    }
+
+    /// Return the latest registry version by querying the registry canister.
+    pub fn get_latest_registry_version(
+        &self,
+        ingress_expiry: Time,
+    ) -> Result<RegistryVersion, String> {
+        match self.query_canister(
+            REGISTRY_CANISTER_ID,
+            "get_latest_version",
             Vec::new(),
-        ) {
-            Ok(wasm_result) => match wasm_result {
-                WasmResult::Reply(v) => {
-                    let bytes = deserialize_get_value_response(v)
-                        .map_err(|err| format!("{}", err))?
-                        .0;
-                    let record =
-                        deserialize_registry_value::<BlessedReplicaVersions>(Ok(Some(bytes)))
-                            .map_err(|err| format!("{}", err))?
-                            .expect("BlessedReplicaVersions does not exist");
-                    Ok(record)
-                }
-                WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
-            },
-            Err(err) => Err(format!("Query failed: {:?}", err)),
+            ingress_expiry,
+        )? {
+            WasmResult::Reply(v) => deserialize_get_latest_version_response(v)
+                .map(RegistryVersion::from)
+                .map_err(|err| format!("{}", err)),
+            WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
+        }
+    }
+
+    /// Like `get_latest_registry_version`, using the default ingress expiry.
+    pub fn get_latest_registry_version_now(&self) -> Result<RegistryVersion, String> {
+        self.get_latest_registry_version(self.default_ingress_expiry())
+    }
+
+    /// Return the highest CatchUpPackage
+    pub fn get_highest_catch_up_package(&self) -> Result<CatchUpPackage, String> {
+        let pool = self
+            .get_consensus_pool()
+            .map_err(|err| format!("{:?}", err))?;
+        Ok(PoolReader::new(pool).get_highest_catch_up_package())
+    }
+
+    /// Return the finalized block at the given height, or `None` if there is
+    /// no such block or no consensus pool.
+    pub fn finalized_block_at(&self, height: Height) -> Option<Block> {
+        let pool = self.consensus_pool.as_ref()?;
+        PoolReader::new(pool).get_finalized_block(height)
+    }
+
+    /// Lazily iterate over the finalized blocks at the heights in the given
+    /// range, e.g. to inspect their payloads before they are executed. Heights
+    /// without a finalized block are skipped, and nothing is returned if there
+    /// is no consensus pool.
+    pub fn iter_finalized_blocks(
+        &self,
+        range: std::ops::Range<Height>,
+    ) -> impl Iterator<Item = Block> + '_ {
+        let pool = self
+            .consensus_pool
+            .as_ref()
+            .map(|pool| PoolReader::new(pool));
+        (range.start.get()..range.end.get()).filter_map(move |height| {
+            pool.as_ref()
+                .and_then(|pool| pool.get_finalized_block(Height::from(height)))
+        })
+    }
+
+    /// Return the number of finalized heights which were not executed yet, i.e.
+    /// the number of batches a replay would deliver, or `None` if there is no
+    /// consensus pool.
+    pub fn execution_gap(&self) -> Option<u64> {
+        let pool = self.consensus_pool.as_ref()?;
+        let finalized_height = PoolReader::new(pool).get_finalized_height();
+        Some(
+            finalized_height
+                .get()
+                .saturating_sub(self.state_manager.latest_state_height().get()),
+        )
+    }
+
+    /// Return the registry version referenced by the last finalized block with
+    /// a context time not later than the given time. Only the finalized blocks
+    /// in the consensus pool are considered, so `None` is returned if the
+    /// given time lies before all of them or there is no consensus pool.
+    pub fn registry_version_at_time(&self, t: Time) -> Option<RegistryVersion> {
+        let pool = PoolReader::new(self.consensus_pool.as_ref()?);
+        let mut height = pool.get_finalized_height();
+        // Block times are monotonic, so we scan the blocks from the top.
+        while let Some(block) = pool.get_finalized_block(height) {
+            if block.context.time <= t {
+                return Some(block.context.registry_version);
+            }
+            if height == Height::from(0) {
+                break;
+            }
+            height = height.decrement();
         }
+        None
     }
 
-    /// Return the latest registry version by querying the registry canister.
-    pub fn get_latest_registry_version(
-        &self,
-        ingress_expiry: Time,
-    ) -> Result<RegistryVersion, String> {
-        let query = UserQuery {
-            source: UserId::from(PrincipalId::new_anonymous()),
-            receiver: REGISTRY_CANISTER_ID,
-            method_name: "get_latest_version".to_string(),
-            method_payload: Vec::new(),
-            ingress_expiry: ingress_expiry.as_nanos_since_unix_epoch(),
-            nonce: None,
-        };
-        match self.http_query_handler.query(
-            query,
-            self.state_manager.get_latest_state().take(),
-            Vec::new(),
-        ) {
-            Ok(wasm_result) => match wasm_result {
-                WasmResult::Reply(v) => deserialize_get_latest_version_response(v)
-                    .map(RegistryVersion::from)
-                    .map_err(|err| format!("{}", err)),
-                WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
-            },
-            Err(err) => Err(format!("Failed run query: {:?}", err)),
-        }
+    /// Drops the consensus and certification pools to release their file
+    /// handles. The state manager and the query handler stay intact, so the
+    /// replayed state can still be inspected.
+    pub fn release_pools(&mut self) {
+        self.consensus_pool = None;
+        self.certification_pool = None;
+        self.pools_released = true;
+        outputln!(self, "Released the consensus and certification pools.");
     }
 
-    /// Return the highest CatchUpPackage
-    pub fn get_highest_catch_up_package(&self) -> CatchUpPackage {
-        PoolReader::new(self.consensus_pool.as_ref().unwrap()).get_highest_catch_up_package()
+    // Returns the consensus pool or an error explaining why it is unavailable.
+    fn get_consensus_pool(&self) -> Result<&ConsensusPoolImpl, ReplayError> {
+        available_pool(self.consensus_pool.as_ref(), self.pools_released)
     }
 
     /// Query the registry canister and return registry records since the given
@@ -661,114 +3022,649 @@ impl Player {
         ingress_expiry: Time,
     ) -> Result<Vec<RegistryTransportRecord>, String> {
         let payload = serialize_get_changes_since_request(version).unwrap();
-        let query = UserQuery {
-            source: UserId::from(PrincipalId::new_anonymous()),
-            receiver: REGISTRY_CANISTER_ID,
-            method_name: "get_changes_since".to_string(),
-            method_payload: payload,
-            ingress_expiry: ingress_expiry.as_nanos_since_unix_epoch(),
-            nonce: None,
-        };
-        match self.http_query_handler.query(
-            query,
-            self.state_manager.get_latest_state().take(),
-            Vec::new(),
-        ) {
-            Ok(wasm_result) => match wasm_result {
-                WasmResult::Reply(v) => deserialize_get_changes_since_response(v)
-                    .and_then(|(deltas, _)| registry_deltas_to_registry_transport_records(deltas))
-                    .map_err(|err| format!("{:?}", err)),
-                WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
-            },
-            Err(err) => Err(format!("Failed run query: {:?}", err)),
+        match self.query_canister(
+            REGISTRY_CANISTER_ID,
+            "get_changes_since",
+            payload,
+            ingress_expiry,
+        )? {
+            WasmResult::Reply(v) => deserialize_get_changes_since_response(v)
+                .and_then(|(deltas, _)| registry_deltas_to_registry_transport_records(deltas))
+                .map_err(|err| format!("{:?}", err)),
+            WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
         }
     }
 
+    /// Like `get_changes_since`, using the default ingress expiry.
+    pub fn get_changes_since_now(
+        &self,
+        version: u64,
+    ) -> Result<Vec<RegistryTransportRecord>, String> {
+        self.get_changes_since(version, self.default_ingress_expiry())
+    }
+
     /// Return the SubnetRecord of this subnet at the latest registry version.
     pub fn get_subnet_record(&self, ingress_expiry: Time) -> Result<SubnetRecord, String> {
+        self.query_subnet_record(ingress_expiry, None)
+    }
+
+    /// Return the SubnetRecord of this subnet at the given registry version
+    /// by querying the registry canister.
+    pub fn subnet_record_at(&self, version: RegistryVersion) -> Result<SubnetRecord, String> {
+        self.query_subnet_record(self.default_ingress_expiry(), Some(version))
+    }
+
+    // Queries the registry canister for the SubnetRecord of this subnet at the
+    // given registry version, or at the latest version if none is given.
+    fn query_subnet_record(
+        &self,
+        ingress_expiry: Time,
+        version: Option<RegistryVersion>,
+    ) -> Result<SubnetRecord, String> {
         let subnet_record_key = make_subnet_record_key(self.subnet_id);
-        let query = UserQuery {
-            source: UserId::from(PrincipalId::new_anonymous()),
-            receiver: REGISTRY_CANISTER_ID,
-            method_name: "get_value".to_string(),
-            method_payload: serialize_get_value_request(
-                subnet_record_key.as_bytes().to_vec(),
-                None,
-            )
-            .map_err(|err| format!("{}", err))?,
-            ingress_expiry: ingress_expiry.as_nanos_since_unix_epoch(),
-            nonce: None,
-        };
-        match self.http_query_handler.query(
-            query,
-            self.state_manager.get_latest_state().take(),
-            Vec::new(),
-        ) {
-            Ok(wasm_result) => match wasm_result {
-                WasmResult::Reply(v) => {
-                    let bytes = deserialize_get_value_response(v)
-                        .map_err(|err| format!("{}", err))?
-                        .0;
-                    let record = deserialize_registry_value::<SubnetRecord>(Ok(Some(bytes)))
-                        .map_err(|err| format!("{}", err))?
-                        .expect("SubnetRecord does not exist");
-                    Ok(record)
+        let payload = serialize_get_value_request(
+            subnet_record_key.as_bytes().to_vec(),
+            version.map(|v| v.get()),
+        )
+        .map_err(|err| format!("{}", err))?;
+        match self.query_canister(REGISTRY_CANISTER_ID, "get_value", payload, ingress_expiry)? {
+            WasmResult::Reply(v) => {
+                let bytes = deserialize_get_value_response(v)
+                    .map_err(|err| format!("{}", err))?
+                    .0;
+                let record = deserialize_registry_value::<SubnetRecord>(Ok(Some(bytes)))
+                    .map_err(|err| format!("{}", err))?
+                    .expect("SubnetRecord does not exist");
+                Ok(record)
+            }
+            WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
+        }
+    }
+
+    /// Like `get_subnet_record`, using the default ingress expiry.
+    pub fn get_subnet_record_now(&self) -> Result<SubnetRecord, String> {
+        self.get_subnet_record(self.default_ingress_expiry())
+    }
+
+    /// Return the ids of all nodes of this subnet according to the latest
+    /// subnet record.
+    pub fn subnet_node_ids(&self) -> Result<Vec<NodeId>, String> {
+        let record = self.get_subnet_record_now()?;
+        Ok(get_node_ids_from_subnet_record(&record))
+    }
+
+    /// Return the ids of all subnets according to the subnet list record at the
+    /// latest version of the local registry.
+    pub fn list_subnets(&self) -> Result<Vec<SubnetId>, String> {
+        let version = self.registry.get_latest_version();
+        self.registry
+            .get_subnet_ids(version)
+            .map_err(|err| format!("Couldn't read the subnet list: {:?}", err))?
+            .ok_or_else(|| format!("No subnet list found at registry version {}", version))
+    }
+
+    /// Return the context times of the first and the last block delivered by
+    /// this player, or `None` if no blocks were delivered yet.
+    pub fn replayed_time_range(&self) -> Option<(Time, Time)> {
+        *self.replayed_time_range.borrow()
+    }
+
+    /// Return true if the replayed subnet is the NNS subnet, i.e. the root
+    /// subnet according to the latest local registry version.
+    pub fn is_nns_subnet(&self) -> bool {
+        match self
+            .registry
+            .get_root_subnet_id(self.registry.get_latest_version())
+        {
+            Ok(Some(root_subnet_id)) => root_subnet_id == self.subnet_id,
+            _ => false,
+        }
+    }
+
+    /// Return the number of nodes of this subnet according to the latest subnet
+    /// record.
+    pub fn subnet_size(&self) -> Result<usize, String> {
+        self.subnet_node_ids().map(|node_ids| node_ids.len())
+    }
+
+    /// Return the controllers of the given canister as found in the latest
+    /// replayed state, or `None` if the canister does not exist.
+    pub fn canister_controllers(&self, canister_id: CanisterId) -> Option<Vec<PrincipalId>> {
+        self.state_manager
+            .get_latest_state()
+            .take()
+            .canister_state(&canister_id)
+            .map(|canister| canister.system_state.controllers.iter().cloned().collect())
+    }
+
+    /// Return the status of the given canister as reported by the management
+    /// canister, computed from the latest replayed state, or `None` if the
+    /// canister does not exist.
+    pub fn canister_status(&self, canister_id: CanisterId) -> Option<CanisterStatusSummary> {
+        let state = self.state_manager.get_latest_state().take();
+        let canister = state.canister_state(&canister_id)?;
+        Some(CanisterStatusSummary {
+            status: canister.status(),
+            cycles: canister.system_state.balance(),
+            memory_size: canister.memory_usage(self.subnet_type),
+            module_hash: canister
+                .execution_state
+                .as_ref()
+                .map(|es| es.wasm_binary.binary.module_hash().to_vec()),
+            controllers: canister.system_state.controllers.iter().cloned().collect(),
+        })
+    }
+
+    /// Return the sizes of the queues of the given canister as found in the
+    /// latest replayed state, or `None` if the canister does not exist.
+    pub fn canister_queue_snapshot(&self, canister_id: CanisterId) -> Option<QueueSnapshot> {
+        self.state_manager
+            .get_latest_state()
+            .take()
+            .canister_state(&canister_id)
+            .map(|canister| {
+                let queues = canister.system_state.queues();
+                QueueSnapshot {
+                    input_queue_len: queues.input_queues_message_count(),
+                    output_queue_len: queues.output_queues_message_count(),
+                    reserved_slots: queues.reserved_slots(),
                 }
-                WasmResult::Reject(e) => Err(format!("Query rejected: {}", e)),
-            },
-            Err(err) => Err(format!("Failed run query: {:?}", err)),
+            })
+    }
+
+    /// Return an iterator over the ingress history of the latest replayed
+    /// state, ordered by message id. The statuses are looked up one by one,
+    /// so the ingress history is never copied as a whole.
+    pub fn ingress_history_iter(&self) -> impl Iterator<Item = (MessageId, IngressStatus)> {
+        let state = self.state_manager.get_latest_state().take();
+        let mut last_message_id: Option<MessageId> = None;
+        std::iter::from_fn(move || {
+            let (message_id, status) = state
+                .get_ingress_history()
+                .statuses_after(last_message_id.as_ref())
+                .next()
+                .map(|(message_id, status)| (message_id.clone(), status.clone()))?;
+            last_message_id = Some(message_id.clone());
+            Some((message_id, status))
+        })
+    }
+
+    /// Return the number of ingress messages still waiting for execution in the
+    /// latest replayed state, including those addressed to the subnet.
+    pub fn pending_ingress_count(&self) -> usize {
+        let state = self.state_manager.get_latest_state().take();
+        state
+            .canisters_iter()
+            .map(|canister| canister.system_state.queues().ingress_queue_message_count())
+            .sum::<usize>()
+            + state.subnet_queues().ingress_queue_message_count()
+    }
+
+    /// Return the memory used by each canister in the latest replayed state,
+    /// including Wasm, stable and message memory, sorted in descending order.
+    pub fn memory_usage_by_canister(&self) -> Vec<(CanisterId, NumBytes)> {
+        let state = self.state_manager.get_latest_state().take();
+        let mut usage: Vec<_> = state
+            .canisters_iter()
+            .map(|canister| {
+                let execution_memory = canister
+                    .execution_state
+                    .as_ref()
+                    .map_or(NumBytes::from(0), |es| es.memory_usage());
+                (
+                    canister.canister_id(),
+                    execution_memory + canister.system_state.memory_usage(),
+                )
+            })
+            .collect();
+        usage.sort_by(|(_, a), (_, b)| b.cmp(a));
+        usage
+    }
+
+    /// Scans the backup spool of this subnet and returns the ordered list of
+    /// replica versions together with the heights at which they take effect.
+    /// Where the local registry already knows the registry version referenced
+    /// by a CUP, the replica version of the spool directory is checked against
+    /// the registry.
+    pub fn replica_version_timeline(&self) -> Result<Vec<(Height, ReplicaVersion)>, String> {
+        let backup_dir = self.backup_dir.as_ref().ok_or("No backup path found")?;
+        let subnet_backup_dir = backup_dir
+            .parent()
+            .ok_or_else(|| format!("Invalid backup path {:?}", backup_dir))?;
+        let start_heights = backup::replica_version_start_heights(subnet_backup_dir)
+            .map_err(|err| format!("File scanning failed: {:?}", err))?;
+
+        let mut timeline = Vec::new();
+        for (height, version) in start_heights {
+            let replica_version = ReplicaVersion::try_from(version.as_str()).map_err(|err| {
+                format!("Couldn't parse the replica version {}: {:?}", version, err)
+            })?;
+            let backup_source = FsBackupSource::new(subnet_backup_dir.join(&version));
+            let cup = backup_source.read_cup(height)?;
+            let registry_version = cup.content.registry_version();
+            if registry_version <= self.registry.get_latest_version() {
+                match ic_consensus::consensus::utils::lookup_replica_version(
+                    &*self.registry,
+                    self.subnet_id,
+                    &ic_logger::replica_logger::no_op_logger(),
+                    registry_version,
+                ) {
+                    Some(registry_replica_version)
+                        if registry_replica_version != replica_version =>
+                    {
+                        return Err(format!(
+                            "The CUP at height {} is stored under replica version {}, but the registry version {} references replica version {}",
+                            height, replica_version, registry_version, registry_replica_version
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            // Only record actual transitions.
+            if timeline.last().map(|(_, v)| v) != Some(&replica_version) {
+                timeline.push((height, replica_version));
+            }
+        }
+        Ok(timeline)
+    }
+
+    /// Scans the backup spool starting from the given height and returns all
+    /// heights with conflicting artifacts, e.g. from a fork. Note that the
+    /// backup spool does not contain certifications, so only consensus
+    /// artifacts are checked.
+    pub fn detect_backup_conflicts(
+        &self,
+        start_height: Height,
+    ) -> Result<Vec<(Height, ConflictKind)>, String> {
+        let backup_source = self
+            .backup_source
+            .as_ref()
+            .ok_or("No backup source found")?;
+        backup::detect_conflicts(backup_source.as_ref(), start_height)
+            .map_err(|err| format!("File scanning failed: {:?}", err))
+    }
+
+    /// Writes the metadata of all heights in the backup spool to the given file
+    /// in CSV format, e.g. to inspect the shape of a spool before restoring it.
+    /// Each row contains the height, the artifact types present, whether the
+    /// height contains a CUP and the replica version of the spool. Returns the
+    /// number of rows written.
+    pub fn export_backup_metadata_csv(&self, dest: &Path) -> Result<usize, String> {
+        let backup_source = self
+            .backup_source
+            .as_ref()
+            .ok_or("No backup source found")?;
+        backup::export_metadata_csv(backup_source.as_ref(), &self.replica_version, dest)
+            .map_err(|err| format!("Exporting the backup metadata failed: {:?}", err))
+    }
+
+    /// Return the protobuf bytes of the CUP at the given height exactly as they
+    /// are stored in the backup spool, so that its signature can still be
+    /// verified by other tools.
+    pub fn read_cup_proto_at_height(&self, height: Height) -> Result<Vec<u8>, String> {
+        let backup_source = self
+            .backup_source
+            .as_ref()
+            .ok_or("No backup source found")?;
+        backup_source.read_cup_bytes(height)
+    }
+
+    /// Return the number of bytes currently occupied by the artifacts in the
+    /// backup spool of this subnet and replica version, e.g. to monitor the
+    /// disk pressure together with `purge_backup_below`.
+    pub fn backup_disk_usage(&self) -> Result<u64, String> {
+        let backup_dir = self.backup_dir.as_ref().ok_or("No backup path found")?;
+        backup::artifacts_size(&FsBackupSource::new(backup_dir.clone()), Height::from(0))
+            .map(|(bytes, _)| bytes)
+            .map_err(|err| format!("File scanning failed: {:?}", err))
+    }
+
+    /// Deletes the backup artifacts strictly below the given height from the
+    /// backup spool and returns the number of bytes reclaimed. The height must
+    /// not exceed the latest state height, and the highest CUP below it is
+    /// always kept, so that the restore can be resumed from it. Note that the
+    /// deleted artifacts cannot be recovered, so this is never done
+    /// automatically.
+    pub fn purge_backup_below(&self, height: Height) -> Result<u64, String> {
+        let backup_dir = self.backup_dir.as_ref().ok_or("No backup path found")?;
+        let latest_state_height = self.state_manager.latest_state_height();
+        if height > latest_state_height {
+            return Err(format!(
+                "Cannot purge backup artifacts below height {}, which was not restored yet (latest state height is {})",
+                height, latest_state_height
+            ));
+        }
+        backup::purge_below(&FsBackupSource::new(backup_dir.clone()), height)
+            .map_err(|err| format!("Purging the backup failed: {:?}", err))
+    }
+
+    /// Restores the execution state from the backup starting from the given
+    /// height, and then delivers the remaining finalized batches from the
+    /// consensus pool. Both phases stop at the given target height, which
+    /// overrides the replay target height of the player only for this call.
+    /// `None` keeps the replay target height of the player.
+    pub fn drain_to_target(&mut self, start_height: u64, target: Option<Height>) -> ReplayResult {
+        let previous_target = self.replay_target_height;
+        if let Some(target) = target {
+            self.replay_target_height = Some(target.get());
+        }
+        let result = self.restore_and_replay(start_height);
+        self.replay_target_height = previous_target;
+        result
+    }
+
+    // Restores the execution state from the backup and delivers the remaining
+    // batches from the consensus pool, unless the replay target height was
+    // reached during the restore already.
+    fn restore_and_replay(&mut self, start_height: u64) -> ReplayResult {
+        let result = self.restore(start_height)?;
+        if let Some(target) = self.replay_target_height {
+            if result.0 >= Height::from(target) {
+                return Ok(result);
+            }
+        }
+        self.replay(|_, _| Vec::new())
+    }
+
+    /// Restores the execution state from the backup spools of several replica
+    /// versions in the given order, starting each of them from the given
+    /// height. The replica version and the backup directory of the player are
+    /// switched between the spools, while the state manager continues across
+    /// the upgrade boundaries. This only works if the replica versions are
+    /// compatible with the binary of this player.
+    pub fn restore_multi_version(
+        &mut self,
+        spool_root: &Path,
+        versions: Vec<(ReplicaVersion, u64)>,
+    ) -> ReplayResult {
+        let mut result = self.get_latest_state_height_and_hash();
+        let version_count = versions.len();
+        for (i, (replica_version, start_height)) in versions.into_iter().enumerate() {
+            let backup_dir = spool_root
+                .join(self.subnet_id.to_string())
+                .join(replica_version.to_string());
+            outputln!(
+                self,
+                "Switching to replica version {} at height {}",
+                replica_version,
+                start_height
+            );
+            self.backup_source = Some(Arc::new(
+                FsBackupSource::new(backup_dir.clone())
+                    .with_read_buffer_size(self.backup_read_buffer_size),
+            ));
+            self.backup_dir = Some(backup_dir);
+            self.replica_version = replica_version;
+            result = self.restore(start_height);
+            match &result {
+                // An upgrade is expected at the end of every spool but the last one.
+                Err(ReplayError::UpgradeDetected(_)) if i + 1 < version_count => {}
+                Ok(_) => {}
+                Err(_) => return result,
+            }
+        }
+        result
+    }
+
+    /// Return the plan of a restore starting from the given height, i.e. the
+    /// heights to be delivered, the CUPs and registry versions passed on the
+    /// way and the upgrades requiring another replica version. No batches are
+    /// delivered. The plan respects the replay target height.
+    pub fn plan_restore(&self, start_height: u64) -> Result<RestorePlan, String> {
+        let backup_source = self
+            .backup_source
+            .as_ref()
+            .ok_or("No backup source found")?;
+        let target_height = self.replay_target_height.map(Height::from);
+        let within_target =
+            |height: &Height| target_height.map_or(true, |target| *height <= target);
+        let (heights, cup_heights) =
+            backup::heights_and_cup_heights(backup_source.as_ref(), Height::from(start_height))
+                .map_err(|err| format!("File scanning failed: {:?}", err))?;
+        let heights: Vec<_> = heights.into_iter().filter(within_target).collect();
+        let cup_boundaries: Vec<_> = cup_heights.into_iter().filter(within_target).collect();
+
+        let mut registry_transitions = Vec::new();
+        let mut version_switches_needed = Vec::new();
+        let mut last_registry_version = None;
+        let mut last_replica_version = None;
+        for height in &cup_boundaries {
+            let cup = backup_source.read_cup(*height)?;
+            let registry_version = cup.content.registry_version();
+            if last_registry_version != Some(registry_version) {
+                registry_transitions.push((*height, registry_version));
+                last_registry_version = Some(registry_version);
+            }
+            let replica_version = self.required_replica_version(&cup);
+            if let Some(version) = &replica_version {
+                if last_replica_version.as_ref() != Some(version) {
+                    version_switches_needed.push((*height, version.clone()));
+                }
+            }
+            last_replica_version = replica_version;
+        }
+
+        Ok(RestorePlan {
+            height_range: heights.first().zip(heights.last()).map(|(f, l)| (*f, *l)),
+            cup_boundaries,
+            registry_transitions,
+            version_switches_needed,
+        })
+    }
+
+    /// Restores the execution state starting from the given height with the
+    /// least possible work and returns only the hash of the final state, e.g.
+    /// for CI gates comparing it with an expected hash. No intermediate
+    /// checkpoints are created, so the state hashes of the CUPs on the way are
+    /// not verified, and no certifications are redelivered. The only checkpoint
+    /// is created by the batch at the last finalized height in the backup,
+    /// bounded by the target height, and its hash is returned.
+    ///
+    /// Unlike the other accessors this takes `&mut self`, because restoring
+    /// inserts the artifacts into the player's consensus pool.
+    pub fn final_state_hash(&mut self, start_height: u64) -> Result<String, ReplayError> {
+        let backup_source = self
+            .backup_source
+            .clone()
+            .ok_or_else(|| ReplayError::BackupError("No backup source found".to_string()))?;
+        let final_height = backup::last_finalized_height(
+            backup_source.as_ref(),
+            Height::from(start_height),
+            self.replay_target_height.map(Height::from),
+        )
+        .map_err(|err| ReplayError::BackupError(format!("File scanning failed: {:?}", err)))?
+        .ok_or_else(|| {
+            ReplayError::BackupError(format!(
+                "No finalized height found from height {}",
+                start_height
+            ))
+        })?;
+        self.skip_intermediate_checkpoints = true;
+        self.final_checkpoint_height = Some(final_height);
+        let result = self.restore(start_height);
+        self.skip_intermediate_checkpoints = false;
+        self.final_checkpoint_height = None;
+        let (height, hash) = result?;
+        final_state_params(final_height, height, hash)
+    }
+
+    /// Resumes an interrupted restore from the highest CUP in the backup spool
+    /// which is not above the latest state height and whose checkpoint still
+    /// exists, or from the CUP the consensus pool was created with if there is
+    /// none, e.g. when the state manager is empty. Batches at heights which
+    /// were already executed are not delivered again.
+    pub fn restore_resume(&mut self) -> ReplayResult {
+        let backup_source = self
+            .backup_source
+            .clone()
+            .ok_or_else(|| ReplayError::BackupError("No backup source found".to_string()))?;
+        let latest_state_height = self.state_manager.latest_state_height();
+        let pool_cup_height = self.get_latest_cup()?.cup.height();
+        let (_, cup_heights) =
+            backup::heights_and_cup_heights(backup_source.as_ref(), pool_cup_height).map_err(
+                |err| ReplayError::BackupError(format!("File scanning failed: {:?}", err)),
+            )?;
+        let resume_point = resume_point(
+            &cup_heights,
+            &self.checkpoint_heights(),
+            latest_state_height,
+            self.executed_height(),
+            pool_cup_height,
+        );
+        let resume_height = resume_point.cup_height;
+        outputln!(
+            self,
+            "Resuming the restore from the CUP at height {}, delivering batches from height {} (latest state height is {})",
+            resume_height,
+            resume_point.first_batch_height,
+            latest_state_height
+        );
+        if resume_height > pool_cup_height {
+            backup::insert_cup_at_height(
+                available_pool(self.consensus_pool.as_mut(), self.pools_released)?,
+                self.time_source.as_ref(),
+                backup_source.as_ref(),
+                resume_height,
+            );
+            self.assert_consistency_and_clean_up()?;
         }
+        // The batch at the resume height is already part of the CUP's state.
+        self.restore(resume_height.get() + 1)
     }
 
     /// Restores the execution state starting from the given height.
     pub fn restore(&mut self, start_height: u64) -> ReplayResult {
         let target_height = self.replay_target_height.map(Height::from);
-        let backup_dir = self
-            .backup_dir
-            .as_ref()
-            .expect("No backup path found")
-            .clone();
+        let backup_source = self
+            .backup_source
+            .clone()
+            .ok_or_else(|| ReplayError::BackupError("No backup source found".to_string()))?;
         let start_height = Height::from(start_height);
         let mut height_to_batches =
-            backup::heights_to_artifacts_metadata(&backup_dir, start_height)
-                .unwrap_or_else(|err| panic!("File scanning failed: {:?}", err));
-        println!(
-            "Restoring the replica state of subnet {:?} starting from the height {:?}",
-            backup_dir, start_height
+            backup::heights_to_artifacts_metadata(backup_source.as_ref(), start_height).map_err(
+                |err| ReplayError::BackupError(format!("File scanning failed: {:?}", err)),
+            )?;
+        let cup_heights: BTreeSet<Height> = height_to_batches
+            .iter()
+            .filter(|(_, artifacts)| artifacts.contains_cup())
+            .map(|(height, _)| *height)
+            .collect();
+        // The highest height in the backup spool, bounded by the target height.
+        let progress_target_height = height_to_batches
+            .keys()
+            .last()
+            .copied()
+            .unwrap_or(start_height)
+            .min(target_height.unwrap_or_else(|| Height::from(u64::MAX)));
+        outputln!(
+            self,
+            "Restoring the replica state of subnet {} starting from the height {:?}",
+            self.subnet_id,
+            start_height
         );
         // Assert consistent initial state
         self.verify_latest_cup()?;
+        // The latest checkpoint seen and the number of checkpoints created
+        // during the restore, for verifying every n-th one.
+        let mut last_seen_checkpoint = self.state_manager.latest_state_height();
+        let mut checkpoint_count = 0;
         // We start with the specified height and restore heights until we run out of
         // heights on the backup spool or bump into a newer replica version.
         loop {
+            let executed_height = self.executed_height();
             let result = backup::deserialize_consensus_artifacts(
                 self.registry.clone(),
-                self.consensus_pool.as_mut().unwrap(),
+                available_pool(self.consensus_pool.as_mut(), self.pools_released)?,
+                self.time_source.as_ref(),
+                backup_source.as_ref(),
                 &mut height_to_batches,
                 self.subnet_id,
-                self.state_manager.latest_state_height(),
+                executed_height,
             );
+            self.emit(ReplayEvent::ExitPoint(format!("{:?}", result)));
 
+            let pool_reader = PoolReader::new(self.get_consensus_pool()?);
             let last_batch_height = self.deliver_batches(
-                &self.message_routing,
-                &PoolReader::new(self.consensus_pool.as_ref().unwrap()),
+                self.message_routing(),
+                &pool_reader,
                 self.replay_target_height.map(Height::from),
             );
-            self.wait_for_state(last_batch_height);
+            self.report_progress(last_batch_height, progress_target_height);
+            let target_time_reached =
+                self.replay_target_time_reached(&pool_reader, last_batch_height);
+            if let Some(certified_height) = pipelined_certified_height(
+                self.pipelined_restore,
+                &result,
+                last_batch_height,
+                target_time_reached,
+                target_height,
+            ) {
+                self.wait_for_executed_height(certified_height);
+                self.state_manager.remove_states_below(certified_height);
+                continue;
+            }
+            self.wait_for_state(last_batch_height)?;
+            if let Some(n) = self.verify_every.filter(|n| *n > 0) {
+                let mut new_checkpoints: Vec<_> = self
+                    .checkpoint_heights()
+                    .into_iter()
+                    .filter(|h| *h > last_seen_checkpoint)
+                    .collect();
+                new_checkpoints.sort();
+                verify_nth_checkpoints(
+                    &new_checkpoints,
+                    checkpoint_count,
+                    n,
+                    &cup_heights,
+                    |height| self.cup_state_hash(backup_source.as_ref(), height),
+                    |height| self.checkpoint_state_hash(height),
+                )
+                .map_err(|err| {
+                    if let ReplayError::StateDivergence {
+                        height,
+                        expected,
+                        actual,
+                    } = &err
+                    {
+                        outputln!(
+                            self,
+                            "The state hash of the checkpoint at height {} differs from the CUP's hash: expected {}, actual {}",
+                            height,
+                            hex::encode(&expected.get_ref().0),
+                            hex::encode(&actual.get_ref().0)
+                        );
+                    }
+                    err
+                })?;
+                checkpoint_count += new_checkpoints.len() as u64;
+                if let Some(height) = new_checkpoints.last() {
+                    last_seen_checkpoint = *height;
+                }
+            }
             if let Some(height) = target_height {
                 if last_batch_height >= height {
-                    println!("Target height {} reached.", height);
-                    return Ok(self.get_latest_state_height_and_hash());
+                    outputln!(self, "Target height {} reached.", height);
+                    return self.get_latest_state_height_and_hash();
                 }
             }
+            if target_time_reached {
+                outputln!(
+                    self,
+                    "Target time {} reached at height {}.",
+                    self.replay_target_time.unwrap(),
+                    last_batch_height
+                );
+                return self.get_latest_state_height_and_hash();
+            }
 
             match result {
                 // Since the pool cache assumes we always have at most one CUP inside the pool,
                 // we should deliver all batches before inserting a new CUP into the pool.
                 backup::ExitPoint::CUPHeightWasFinalized(cup_height) => {
                     backup::insert_cup_at_height(
-                        self.consensus_pool.as_mut().unwrap(),
-                        &backup_dir,
+                        available_pool(self.consensus_pool.as_mut(), self.pools_released)?,
+                        self.time_source.as_ref(),
+                        backup_source.as_ref(),
                         cup_height,
                     );
                     self.assert_consistency_and_clean_up()?;
@@ -785,132 +3681,704 @@ impl Player {
                         "The registry client couldn't be updated to version {:?} (highest available version is {:?})",
                         new_version, self.registry.get_latest_version()
                     );
-                    println!("Updated the registry.");
+                    outputln!(self, "Updated the registry.");
                 }
                 backup::ExitPoint::StateBehind(certified_height) => {
                     assert!(
-                        certified_height <= self.state_manager.latest_state_height(),
+                        certified_height <= self.executed_height(),
                         "The state manager didn't catch up with the expected certified height"
                     );
                     self.state_manager.remove_states_below(certified_height);
                 }
                 backup::ExitPoint::Done => {
-                    println!(
+                    outputln!(
+                        self,
                         "Restored the state at the height {:?}",
                         self.state_manager.latest_state_height()
                     );
-                    return Ok(self.get_latest_state_height_and_hash());
+                    return self.get_latest_state_height_and_hash();
+                }
+            }
+        }
+    }
+
+    // Returns the state hash of the CUP at the given height in the backup spool.
+    fn cup_state_hash(
+        &self,
+        source: &dyn BackupSource,
+        height: Height,
+    ) -> Result<CryptoHashOfState, ReplayError> {
+        let cup = source.read_cup(height).map_err(ReplayError::BackupError)?;
+        Ok(cup.content.state_hash)
+    }
+
+    // Returns the state hash of the checkpoint at the given height.
+    fn checkpoint_state_hash(&self, height: Height) -> Result<CryptoHashOfState, ReplayError> {
+        self.get_state_hash(height)?
+            .ok_or_else(|| ReplayError::StateComputationFailed {
+                height,
+                detail: "No state hash found for the checkpoint".to_string(),
+            })
+    }
+
+    // Checks that the restored catch-up package contains the same state hash as
+    // the one computed by the state manager from the restored artifacts and drops
+    // all states below the last CUP.
+    fn assert_consistency_and_clean_up(&mut self) -> Result<StateParams, ReplayError> {
+        self.verify_latest_cup()?;
+        let params = self.get_latest_state_height_and_hash()?;
+        let purge_height = self
+            .get_consensus_pool()?
+            .get_cache()
+            .catch_up_package()
+            .height();
+        outputln!(self, "Removing all states below height {:?}", purge_height);
+        self.state_manager.remove_states_below(purge_height);
+        use ic_interfaces::consensus_pool::{ChangeAction, MutableConsensusPool};
+        let pool = available_pool(self.consensus_pool.as_mut(), self.pools_released)?;
+        pool.apply_changes(
+            self.time_source.as_ref(),
+            ChangeAction::PurgeValidatedBelow(purge_height).into(),
+        );
+        Ok(params)
+    }
+
+    fn get_latest_cup(&self) -> Result<CUPWithOriginalProtobuf, ReplayError> {
+        Ok(self.get_consensus_pool()?.get_cache().cup_with_protobuf())
+    }
+
+    /// Check that the replica version of this player is blessed according to
+    /// the local registry, and that it matches the replica version referenced
+    /// by the latest CUP in the consensus pool. Replaying with a wrong replica
+    /// version typically results in a state divergence.
+    pub fn validate_replica_version(&self) -> Result<(), String> {
+        let registry_version = self.registry.get_latest_version();
+        let blessed_versions = deserialize_registry_value::<BlessedReplicaVersions>(
+            self.registry
+                .get_value(&make_blessed_replica_version_key(), registry_version),
+        )
+        .map_err(|err| format!("Couldn't read the blessed replica versions: {}", err))?
+        .ok_or_else(|| {
+            format!(
+                "No blessed replica versions found at registry version {}",
+                registry_version
+            )
+        })?;
+        if !blessed_versions
+            .blessed_version_ids
+            .iter()
+            .any(|id| id == self.replica_version.as_ref())
+        {
+            return Err(format!(
+                "Replica version {} is not blessed at registry version {}",
+                self.replica_version, registry_version
+            ));
+        }
+
+        let cup = self
+            .get_latest_cup()
+            .map_err(|err| format!("{:?}", err))?
+            .cup;
+        match ic_consensus::consensus::utils::lookup_replica_version(
+            &*self.registry,
+            self.subnet_id,
+            &ic_logger::replica_logger::no_op_logger(),
+            cup.content.registry_version(),
+        ) {
+            Some(replica_version) if replica_version != self.replica_version => Err(format!(
+                "Replica version {} doesn't match the version {} referenced by the CUP at height {}",
+                self.replica_version,
+                replica_version,
+                cup.height()
+            )),
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "Couldn't look up the replica version referenced by the CUP at height {}",
+                cup.height()
+            )),
+        }
+    }
+
+    /// Return the state sync message of the checkpoint at the given height, so
+    /// that the replayed state can be served to other nodes via state sync.
+    pub fn state_sync_artifact(&self, height: Height) -> Result<StateSyncMessage, String> {
+        let root_hash = self
+            .state_manager
+            .get_state_hash_at(height)
+            .map_err(|err| format!("No state hash found at height {}: {:?}", height, err))?;
+        self.state_manager
+            .get_validated_by_identifier(&StateSyncArtifactId {
+                height,
+                hash: root_hash,
+            })
+            .ok_or_else(|| format!("No state sync artifact found at height {}", height))
+    }
+
+    /// Return the state hash (in hex) attested by the latest CUP in the pool.
+    pub fn last_cup_state_hash(&self) -> Result<String, ReplayError> {
+        Ok(hex::encode(
+            &self.get_latest_cup()?.cup.content.state_hash.get().0,
+        ))
+    }
+
+    /// Checks that the catch-up package inside the consensus pool contains the same state hash as
+    /// the one computed by the state manager. Additionally, it verifies the CUP's signature.
+    pub fn verify_latest_cup(&self) -> Result<(), ReplayError> {
+        self.verify_latest_cup_impl(None)
+    }
+
+    /// Like `verify_latest_cup`, but verifies the CUP's signature against the
+    /// given public key instead of the one of the subnet in the registry, e.g.
+    /// for a CUP of a recovered subnet before the registry was updated.
+    pub fn verify_latest_cup_with_key(
+        &self,
+        pubkey: ThresholdSigPublicKey,
+    ) -> Result<(), ReplayError> {
+        self.verify_latest_cup_impl(Some(&pubkey))
+    }
+
+    // Verifies the latest CUP's signature against the given public key, or the
+    // one in the registry if none is given, and compares its state hash.
+    fn verify_latest_cup_impl(
+        &self,
+        pubkey: Option<&ThresholdSigPublicKey>,
+    ) -> Result<(), ReplayError> {
+        let last_cup_with_proto = self.get_latest_cup()?;
+        let last_cup = last_cup_with_proto.cup;
+
+        // We cannot verify the genesis CUP with this subnet's public key. And there is no state.
+        if last_cup.height() == Height::from(0) {
+            return Ok(());
+        }
+
+        // Verify the CUP signature.
+        let protobuf = last_cup_with_proto.protobuf;
+        let signature = CombinedThresholdSigOf::new(CombinedThresholdSig(protobuf.signature));
+        let content = CatchUpContentProtobufBytes(protobuf.content);
+        let verification = match pubkey {
+            Some(pubkey) => ic_crypto::verify_combined_threshold_sig(&content, &signature, pubkey),
+            None => ic_crypto::CryptoComponentFatClient::new_for_verification_only(
+                self.registry.clone(),
+            )
+            .verify_combined_threshold_sig_by_public_key(
+                &signature,
+                &content,
+                self.subnet_id,
+                last_cup.content.block.get_value().context.registry_version,
+            ),
+        };
+        self.audit(format!(
+            "cup height={} registry_version={} state_hash={} signature={}",
+            last_cup.height(),
+            last_cup.content.registry_version(),
+            hex::encode(&last_cup.content.state_hash.get_ref().0),
+            if verification.is_ok() {
+                "valid"
+            } else {
+                "invalid"
+            }
+        ));
+        verification.map_err(|err| ReplayError::InvalidCupSignature(format!("{:?}", err)))?;
+
+        // There are no states to compare in consensus only mode, and no
+        // checkpoints at the CUP heights if intermediate checkpoints are skipped.
+        if self.skip_intermediate_checkpoints {
+            self.warn(ReplayWarning::CupComparisonSkipped(last_cup.height()));
+        } else if self.no_op_message_routing.is_none() {
+            if last_cup.height() < self.state_manager.latest_state_height() {
+                // In subnet recovery mode we persist states but do not create newer CUPs, hence we cannot
+                // assume anymore that every CUP has a corresponding checkpoint. So if we know that the
+                // latest checkpoint is above the latest CUP height, we should not compare state hashes.
+                self.warn(ReplayWarning::CupComparisonSkipped(last_cup.height()));
+                return Ok(());
+            }
+            self.verify_cup_state_hash(&last_cup)?;
+        }
+
+        if let Some(replica_version) = self.required_replica_version(&last_cup) {
+            outputln!(
+                self,
+                "⚠️  Please use the replay tool of version {} to continue backup recovery from height {:?}",
+                replica_version,
+                last_cup.height()
+            );
+            return Err(ReplayError::UpgradeDetected(
+                self.get_latest_state_height_and_hash()?,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Returns the replica version of the subnet at the registry version of the
+    // given CUP, if it differs from the replica version of this player.
+    fn required_replica_version(&self, cup: &CatchUpPackage) -> Option<ReplicaVersion> {
+        ic_consensus::consensus::utils::lookup_replica_version(
+            &*self.registry,
+            self.subnet_id,
+            &ic_logger::replica_logger::no_op_logger(),
+            cup.content.registry_version(),
+        )
+        .filter(|replica_version| *replica_version != self.replica_version)
+    }
+
+    /// Return the indices of the chunks of the manifest of the local checkpoint
+    /// at the given height whose hashes differ from the ones in the given
+    /// reference manifest, e.g. exported from a healthy node, to narrow down a
+    /// `StateDivergence`.
+    pub fn diverging_manifest_chunks(
+        &self,
+        height: Height,
+        reference: &Manifest,
+    ) -> Result<Vec<usize>, String> {
+        let local = self.export_manifest(height)?;
+        Ok(differing_manifest_chunks(&local, reference))
+    }
+
+    // Compares the state hash in the given CUP with the local state hash and
+    // lets the divergence handler decide how to proceed on a mismatch.
+    fn verify_cup_state_hash(&self, last_cup: &CatchUpPackage) -> Result<(), ReplayError> {
+        // Verify state hash against the state hash in the CUP
+        let local_state_hash = self
+            .get_state_hash(last_cup.height())?
+            .expect("No hash for CUP found");
+        if local_state_hash != last_cup.content.state_hash {
+            let cup_hash = hex::encode(&last_cup.content.state_hash.get_ref().0);
+            let local_hash = hex::encode(&local_state_hash.get().0);
+            outputln!(
+                self,
+                "The state hash of the CUP at height {:?} differs from the local state's hash: expected {}, actual {}",
+                last_cup.height(),
+                cup_hash,
+                local_hash
+            );
+            self.divergence_log.borrow_mut().push((
+                last_cup.height(),
+                cup_hash.clone(),
+                local_hash.clone(),
+            ));
+            let action = match self.divergence_handler.borrow_mut().as_mut() {
+                Some(handler) => handler(last_cup.height(), &cup_hash, &local_hash),
+                None if self.tolerate_divergence => DivergenceAction::Continue,
+                None => DivergenceAction::Abort,
+            };
+            match action {
+                DivergenceAction::Continue => {
+                    outputln!(
+                        self,
+                        "⚠️  Continuing the replay despite the state divergence"
+                    );
+                    self.warn(ReplayWarning::DivergenceTolerated(last_cup.height()));
+                }
+                DivergenceAction::Abort => {
+                    return Err(ReplayError::StateDivergence {
+                        height: last_cup.height(),
+                        expected: last_cup.content.state_hash.clone(),
+                        actual: local_state_hash,
+                    })
+                }
+                DivergenceAction::Rollback(height) => {
+                    outputln!(self, "Rollback to height {} requested", height);
+                    return Err(ReplayError::RollbackRequested(height));
                 }
             }
         }
+        Ok(())
+    }
+}
+
+/// Restores the states of several subnets concurrently, each `Player` on its
+/// own thread, starting from the corresponding height in `start_heights`.
+/// Every `Player` must use a distinct state manager directory.
+///
+/// The results are returned in the order in which the restores complete, not
+/// in the order of `players`. As soon as one of the subnets reports a
+/// `StateDivergence`, which is also written to the output of its `Player`, the
+/// results collected so far are returned. The remaining restores can't be
+/// interrupted and keep running in the background until they complete, their
+/// results are discarded. Otherwise all threads are joined, and a panic of any
+/// restore is propagated.
+pub fn restore_subnets(
+    players: Vec<Player>,
+    start_heights: Vec<u64>,
+) -> Vec<(SubnetId, ReplayResult)> {
+    assert_eq!(
+        players.len(),
+        start_heights.len(),
+        "Every player requires exactly one start height"
+    );
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let handles: Vec<_> = players
+        .into_iter()
+        .zip(start_heights)
+        .map(|(mut player, start_height)| {
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let result = player.restore(start_height);
+                if let Err(ReplayError::StateDivergence { .. }) = result {
+                    outputln!(
+                        player,
+                        "The state of subnet {} diverged, aborting.",
+                        player.subnet_id
+                    );
+                }
+                // The receiver may be gone if another subnet diverged.
+                let _ = sender.send((player.subnet_id, result));
+            })
+        })
+        .collect();
+    // Only the threads hold a sender now, so the receiver stops once all of
+    // them finished, even if some of them panicked.
+    drop(sender);
+
+    let mut results = Vec::new();
+    for (subnet_id, result) in receiver.iter() {
+        let diverged = matches!(result, Err(ReplayError::StateDivergence { .. }));
+        results.push((subnet_id, result));
+        if diverged {
+            return results;
+        }
+    }
+    for handle in handles {
+        if let Err(err) = handle.join() {
+            std::panic::resume_unwind(err);
+        }
+    }
+    results
+}
+
+// Queries the registry canister for the value of the given key at the latest
+// version. Returns `None` if the key is not present.
+fn query_registry_value(
+    query_handler: &dyn QueryHandler<State = ReplicatedState>,
+    state: Arc<ReplicatedState>,
+    key: &str,
+    ingress_expiry: Time,
+) -> Result<Option<Vec<u8>>, String> {
+    let query = UserQuery {
+        source: UserId::from(PrincipalId::new_anonymous()),
+        receiver: REGISTRY_CANISTER_ID,
+        method_name: "get_value".to_string(),
+        method_payload: serialize_get_value_request(key.as_bytes().to_vec(), None)
+            .map_err(|err| format!("{}", err))?,
+        ingress_expiry: ingress_expiry.as_nanos_since_unix_epoch(),
+        nonce: None,
+    };
+    match query_handler.query(query, state, Vec::new()) {
+        Ok(WasmResult::Reply(v)) => match deserialize_get_value_response(v) {
+            Ok((bytes, _)) => Ok(Some(bytes)),
+            Err(RegistryTransportError::KeyNotPresent(_)) => Ok(None),
+            Err(err) => Err(format!("{}", err)),
+        },
+        Ok(WasmResult::Reject(e)) => Err(format!("Query rejected: {}", e)),
+        Err(err) => Err(format!("Query failed: {:?}", err)),
     }
+}
 
-    // Checks that the restored catch-up package contains the same state hash as
-    // the one computed by the state manager from the restored artifacts and drops
-    // all states below the last CUP.
-    fn assert_consistency_and_clean_up(&mut self) -> Result<StateParams, ReplayError> {
-        self.verify_latest_cup()?;
-        let params = self.get_latest_state_height_and_hash();
-        let pool = self.consensus_pool.as_mut().expect("no consensus_pool");
-        let cache = pool.get_cache();
-        let purge_height = cache.catch_up_package().height();
-        println!("Removing all states below height {:?}", purge_height);
-        self.state_manager.remove_states_below(purge_height);
-        use ic_interfaces::{
-            consensus_pool::{ChangeAction, MutableConsensusPool},
-            time_source::SysTimeSource,
-        };
-        pool.apply_changes(
-            &SysTimeSource::new(),
-            ChangeAction::PurgeValidatedBelow(purge_height).into(),
-        );
-        Ok(params)
+// Returns the indices of the chunks whose hashes differ between the given
+// manifests, including the chunks present in only one of them.
+fn differing_manifest_chunks(manifest: &Manifest, other: &Manifest) -> Vec<usize> {
+    let common_chunks = manifest.chunk_table.len().min(other.chunk_table.len());
+    let all_chunks = manifest.chunk_table.len().max(other.chunk_table.len());
+    manifest
+        .chunk_table
+        .iter()
+        .zip(other.chunk_table.iter())
+        .enumerate()
+        .filter(|(_, (chunk, other_chunk))| chunk.hash != other_chunk.hash)
+        .map(|(index, _)| index)
+        .chain(common_chunks..all_chunks)
+        .collect()
+}
+
+// Computes the manifest of the checkpoint with the given layout.
+fn compute_checkpoint_manifest(
+    cp_layout: &CheckpointLayout<ReadOnly>,
+    log: &ReplicaLogger,
+) -> Result<Manifest, String> {
+    let metadata = cp_layout.system_metadata().deserialize().map_err(|err| {
+        format!(
+            "Failed to deserialize system metadata to determine the manifest version: {}",
+            err
+        )
+    })?;
+    let mut thread_pool =
+        scoped_threadpool::Pool::new(ic_state_manager::NUMBER_OF_CHECKPOINT_THREADS);
+    compute_manifest(
+        &mut thread_pool,
+        &ManifestMetrics::new(&MetricsRegistry::new()),
+        log,
+        metadata.state_sync_version,
+        cp_layout.raw_path(),
+        DEFAULT_CHUNK_SIZE,
+        None,
+    )
+    .map_err(|err| {
+        format!(
+            "Failed to compute manifest of checkpoint at {}: {}",
+            cp_layout.raw_path().display(),
+            err
+        )
+    })
+}
+
+// Returns the total size in bytes of all files in the given directory and its
+// subdirectories.
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            bytes += dir_size(&entry.path())?;
+        } else {
+            bytes += entry.metadata()?.len();
+        }
     }
+    Ok(bytes)
+}
 
-    fn get_latest_cup(&self) -> CUPWithOriginalProtobuf {
-        let pool = self
-            .consensus_pool
-            .as_ref()
-            .expect("no consensus_pool")
-            .get_cache();
-        pool.cup_with_protobuf()
+// Copies the directory `src` with all its contents to `dst`, preserving the
+// sparseness of the files.
+fn copy_dir_recursively(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let dst_path = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_recursively(&entry.path(), &dst_path)?;
+        } else {
+            ic_utils::fs::copy_file_sparse(&entry.path(), &dst_path)?;
+        }
     }
+    Ok(())
+}
 
-    /// Checks that the catch-up package inside the consensus pool contains the same state hash as
-    /// the one computed by the state manager. Additionally, it verifies the CUP's signature.
-    pub fn verify_latest_cup(&self) -> Result<(), ReplayError> {
-        let last_cup_with_proto = self.get_latest_cup();
-        let last_cup = last_cup_with_proto.cup;
+// Message routing which discards all batches and only advances the expected
+// batch height.
+struct NoOpMessageRouting {
+    expected_batch_height: AtomicU64,
+}
 
-        // We cannot verify the genesis CUP with this subnet's public key. And there is no state.
-        if last_cup.height() == Height::from(0) {
-            return Ok(());
+impl MessageRouting for NoOpMessageRouting {
+    fn deliver_batch(&self, batch: Batch) -> Result<(), MessageRoutingError> {
+        let expected_height = self.expected_batch_height();
+        if batch.batch_number != expected_height {
+            return Err(MessageRoutingError::Ignored {
+                expected_height,
+                actual_height: batch.batch_number,
+            });
         }
+        self.expected_batch_height
+            .store(expected_height.increment().get(), Ordering::SeqCst);
+        Ok(())
+    }
 
-        // Verify the CUP signature.
-        let protobuf = last_cup_with_proto.protobuf;
-        let crypto =
-            ic_crypto::CryptoComponentFatClient::new_for_verification_only(self.registry.clone());
-        crypto
-            .verify_combined_threshold_sig_by_public_key(
-                &CombinedThresholdSigOf::new(CombinedThresholdSig(protobuf.signature)),
-                &CatchUpContentProtobufBytes(protobuf.content),
-                self.subnet_id,
-                last_cup.content.block.get_value().context.registry_version,
-            )
-            .expect("Verification of the signature on the CUP failed");
+    fn expected_batch_height(&self) -> Height {
+        Height::from(self.expected_batch_height.load(Ordering::SeqCst))
+    }
+}
 
-        if last_cup.height() < self.state_manager.latest_state_height() {
-            // In subnet recovery mode we persist states but do not create newer CUPs, hence we cannot
-            // assume anymore that every CUP has a corresponding checkpoint. So if we know that the
-            // latest checkpoint is above the latest CUP height, we should not compare state hashes.
-            return Ok(());
-        }
+// Message routing forwarding batches with only the ingress messages addressed
+// to the allowlisted canisters.
+struct AllowlistMessageRouting<'a> {
+    message_routing: &'a dyn MessageRouting,
+    allowlist: &'a HashSet<CanisterId>,
+}
 
-        // Verify state hash against the state hash in the CUP
-        if get_state_hash(&*self.state_manager, last_cup.height()).expect("No hash for CUP found")
-            != last_cup.content.state_hash
-        {
-            println!(
-                "The state hash of the CUP at height {:?} differs from the local state's hash",
-                last_cup.height()
-            );
-            return Err(ReplayError::StateDivergence(last_cup.height()));
-        }
+impl MessageRouting for AllowlistMessageRouting<'_> {
+    fn deliver_batch(&self, mut batch: Batch) -> Result<(), MessageRoutingError> {
+        let ingress = Vec::<SignedIngress>::try_from(batch.payload.ingress)
+            .expect("Couldn't deserialize the ingress payload");
+        batch.payload.ingress = IngressPayload::from(
+            ingress
+                .into_iter()
+                .filter(|msg| self.allowlist.contains(&msg.canister_id()))
+                .collect::<Vec<_>>(),
+        );
+        self.message_routing.deliver_batch(batch)
+    }
 
-        match ic_consensus::consensus::utils::lookup_replica_version(
-            &*self.registry,
-            self.subnet_id,
-            &ic_logger::replica_logger::no_op_logger(),
-            last_cup.content.registry_version(),
-        ) {
-            Some(replica_version) if replica_version != self.replica_version => {
-                println!(
-                    "⚠️  Please use the replay tool of version {} to continue backup recovery from height {:?}",
-                    replica_version, last_cup.height()
-                );
-                return Err(ReplayError::UpgradeDetected(
-                    self.get_latest_state_height_and_hash(),
-                ));
-            }
-            _ => {}
-        }
+    fn expected_batch_height(&self) -> Height {
+        self.message_routing.expected_batch_height()
+    }
+}
+
+// Message routing forwarding batches without requesting a checkpoint.
+struct CheckpointlessMessageRouting<'a> {
+    message_routing: &'a dyn MessageRouting,
+    // The only height at which a checkpoint is created, if any.
+    checkpoint_height: Option<Height>,
+}
 
+impl MessageRouting for CheckpointlessMessageRouting<'_> {
+    fn deliver_batch(&self, mut batch: Batch) -> Result<(), MessageRoutingError> {
+        batch.requires_full_state_hash = Some(batch.batch_number) == self.checkpoint_height;
+        self.message_routing.deliver_batch(batch)
+    }
+
+    fn expected_batch_height(&self) -> Height {
+        self.message_routing.expected_batch_height()
+    }
+}
+
+// Message routing writing the execution inputs of every successfully delivered
+// batch to the execution trace, if there is one.
+struct TracingMessageRouting<'a> {
+    message_routing: &'a dyn MessageRouting,
+    execution_trace: &'a RefCell<Option<Box<dyn Write + Send>>>,
+}
+
+impl MessageRouting for TracingMessageRouting<'_> {
+    fn deliver_batch(&self, batch: Batch) -> Result<(), MessageRoutingError> {
+        let mut execution_trace = self.execution_trace.borrow_mut();
+        let writer = match execution_trace.as_mut() {
+            Some(writer) => writer,
+            None => return self.message_routing.deliver_batch(batch),
+        };
+        let ingress = Vec::<SignedIngress>::try_from(batch.payload.ingress.clone())
+            .expect("Couldn't deserialize the ingress payload");
+        let line = serde_json::json!({
+            "batch_number": batch.batch_number.get(),
+            "time": batch.time.as_nanos_since_unix_epoch(),
+            "randomness": hex::encode(batch.randomness.get_ref()),
+            "registry_version": batch.registry_version.get(),
+            "ingress": ingress.iter().map(|msg| msg.id().to_string()).collect::<Vec<_>>(),
+        });
+        self.message_routing.deliver_batch(batch)?;
+        writeln!(writer, "{}", line)
+            .and_then(|_| writer.flush())
+            .expect("Couldn't write to the execution trace");
         Ok(())
     }
+
+    fn expected_batch_height(&self) -> Height {
+        self.message_routing.expected_batch_height()
+    }
+}
+
+// Sets up the execution environment and message routing on top of the given
+// state manager, optionally overriding the instruction limit per message.
+#[allow(clippy::too_many_arguments)]
+fn setup_execution(
+    log: &ReplicaLogger,
+    metrics_registry: &MetricsRegistry,
+    subnet_id: SubnetId,
+    subnet_type: SubnetType,
+    mut hypervisor_config: HypervisorConfig,
+    cycles_account_manager: Arc<CyclesAccountManager>,
+    state_manager: Arc<StateManagerImpl>,
+    registry: Arc<RegistryClientImpl>,
+    instruction_limit_override: Option<NumInstructions>,
+) -> (
+    MessageRoutingImpl,
+    Arc<dyn QueryHandler<State = ReplicatedState>>,
+    Box<dyn IngressHistoryReader>,
+) {
+    let mut subnet_config = SubnetConfigs::default().own_subnet_config(subnet_type);
+    if let Some(max_instructions) = instruction_limit_override {
+        subnet_config.scheduler_config.max_instructions_per_message = max_instructions;
+        hypervisor_config.max_instructions_for_message_acceptance_calls = max_instructions;
+    }
+    let execution_service = ExecutionServices::setup_execution(
+        log.clone(),
+        metrics_registry,
+        subnet_id,
+        subnet_type,
+        subnet_config.scheduler_config,
+        hypervisor_config.clone(),
+        Arc::clone(&cycles_account_manager),
+        Arc::clone(&state_manager) as Arc<_>,
+    );
+    let message_routing = MessageRoutingImpl::new(
+        state_manager.clone(),
+        state_manager,
+        execution_service.ingress_history_writer.clone(),
+        execution_service.scheduler,
+        hypervisor_config,
+        cycles_account_manager,
+        subnet_id,
+        metrics_registry,
+        log.clone(),
+        registry,
+    );
+    (
+        message_routing,
+        execution_service.sync_query_handler,
+        execution_service.ingress_history_reader,
+    )
+}
+
+// The point from which an interrupted restore is resumed.
+#[derive(Debug, PartialEq, Eq)]
+struct ResumePoint {
+    // The height of the CUP the consensus pool starts from.
+    cup_height: Height,
+    // The height of the first batch delivered. The batches between the CUP and
+    // the executed height are skipped by the message routing.
+    first_batch_height: Height,
+}
+
+// Returns the point to resume a restore from: the highest of the given CUP
+// heights which is not above the latest state height and whose checkpoint
+// still exists, or the CUP height of the consensus pool if there is none,
+// e.g. when the state manager is empty.
+fn resume_point(
+    cup_heights: &[Height],
+    checkpoint_heights: &[Height],
+    latest_state_height: Height,
+    executed_height: Height,
+    pool_cup_height: Height,
+) -> ResumePoint {
+    let cup_height = cup_heights
+        .iter()
+        .copied()
+        .filter(|height| *height <= latest_state_height && checkpoint_heights.contains(height))
+        .fold(pool_cup_height, Height::max);
+    ResumePoint {
+        cup_height,
+        first_batch_height: cup_height.max(executed_height).increment(),
+    }
 }
 
+// Estimates the peak size of the state directory during a restore from the
+// size of the checkpoint at the start height and the size of the restored
+// artifacts. The tip is a copy of the latest checkpoint. When the next
+// checkpoint is created, the tip is promoted to the checkpoint and copied into
+// a new tip, while the checkpoint at the start height is only removed later.
+// So there are up to three copies of the state at a time, of which the two
+// newer ones have grown at least by the size of the artifacts.
+fn estimate_peak_state_bytes(checkpoint_bytes: u64, artifact_bytes: u64) -> u64 {
+    checkpoint_bytes + 2 * (checkpoint_bytes + artifact_bytes)
+}
+
+// Returns the highest version of the given records, or the known version if
+// there are no newer records.
+fn latest_record_version(
+    known_version: RegistryVersion,
+    records: &[RegistryTransportRecord],
+) -> RegistryVersion {
+    records
+        .iter()
+        .map(|record| record.version)
+        .fold(known_version, RegistryVersion::max)
+}
+
+// Writes the given records to the local store and reports the written
+// versions to the given output.
 fn write_records_to_local_store(
     local_store_path: &Path,
     latest_version: RegistryVersion,
     mut records: Vec<RegistryTransportRecord>,
+    output: &mut dyn Write,
 ) {
     let local_store = LocalStoreImpl::new(local_store_path);
-    println!(
+    writeln!(
+        output,
         "Found {:?} deltas in registry canister since version {:?}",
         records.len(),
         latest_version
-    );
+    )
+    .expect("Couldn't write to the output");
     records.sort_by_key(|tr| tr.version);
     let changelog = records.iter().fold(Changelog::default(), |mut cl, r| {
         let rel_version = (r.version - latest_version).get();
@@ -929,7 +4397,8 @@ fn write_records_to_local_store(
         .enumerate()
         .try_for_each(|(i, cle)| {
             let v = latest_version + RegistryVersion::from(i as u64 + 1);
-            println!("Writing data of registry version {}", v);
+            writeln!(output, "Writing data of registry version {}", v)
+                .expect("Couldn't write to the output");
             local_store.store(v, cle)
         })
         .expect("Writing to the file system failed: Stop.");
@@ -954,28 +4423,513 @@ fn setup_registry(
     registry
 }
 
-// Returns the state hash for the given height once it is computed. For non-checkpoints heights
-// `None` is returned.
-fn get_state_hash<T>(
-    state_manager: &dyn StateManager<State = T>,
-    height: Height,
-) -> Option<CryptoHashOfState> {
-    loop {
-        match state_manager.get_state_hash_at(height) {
-            Ok(hash) => return Some(hash),
-            Err(StateHashError::Transient(err)) => {
-                println!("Waiting for state hash: {:?}", err);
-            }
-            // This only happens for partially certified heights.
-            Err(StateHashError::Permanent(PermanentStateHashError::StateNotFullyCertified(h)))
-                if h == height =>
-            {
-                return None
+// Returns the certified height to wait for before deserializing the next
+// artifacts, if the restore can continue without waiting for the state of the
+// last delivered batch. In the pipelined mode this is the case if the
+// deserialization only stopped for a state which is already delivered. All
+// other exit points, e.g. the insertion of a CUP, and reaching the target
+// height or time synchronize with the state computation as in the sequential
+// mode.
+fn pipelined_certified_height(
+    pipelined_restore: bool,
+    exit_point: &backup::ExitPoint,
+    last_batch_height: Height,
+    target_time_reached: bool,
+    target_height: Option<Height>,
+) -> Option<Height> {
+    match exit_point {
+        backup::ExitPoint::StateBehind(certified_height)
+            if pipelined_restore
+                && !target_time_reached
+                && *certified_height <= last_batch_height
+                && target_height.map_or(true, |height| last_batch_height < height) =>
+        {
+            Some(*certified_height)
+        }
+        _ => None,
+    }
+}
+
+// Verifies every `n`-th of the given new checkpoints, counting from the
+// `checkpoint_count` checkpoints created before them. Only CUPs carry a state
+// hash to compare with, so a checkpoint is verified by comparing the state hash
+// of the nearest CUP at or below it with the hash of the checkpoint this CUP
+// certifies. Returns the first divergence.
+fn verify_nth_checkpoints(
+    new_checkpoints: &[Height],
+    checkpoint_count: u64,
+    n: u64,
+    cup_heights: &BTreeSet<Height>,
+    cup_state_hash: impl Fn(Height) -> Result<CryptoHashOfState, ReplayError>,
+    checkpoint_state_hash: impl Fn(Height) -> Result<CryptoHashOfState, ReplayError>,
+) -> Result<(), ReplayError> {
+    let verified_cup_heights: BTreeSet<_> = new_checkpoints
+        .iter()
+        .zip(checkpoint_count + 1..)
+        .filter(|(_, count)| count % n == 0)
+        .filter_map(|(height, _)| cup_heights.range(..=*height).next_back().copied())
+        .collect();
+    for height in verified_cup_heights {
+        let expected = cup_state_hash(height)?;
+        let actual = checkpoint_state_hash(height)?;
+        if expected != actual {
+            return Err(ReplayError::StateDivergence {
+                height,
+                expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Returns the final state params of a restore which was supposed to create its
+// only checkpoint at the `final_height`, or an error if the restore ended at a
+// different height, so that the returned hash isn't the one of another height.
+fn final_state_params(
+    final_height: Height,
+    restored_height: Height,
+    hash: String,
+) -> Result<String, ReplayError> {
+    if restored_height != final_height {
+        return Err(ReplayError::StateHeightMismatch {
+            expected: final_height,
+            actual: restored_height,
+        });
+    }
+    Ok(hash)
+}
+
+// Returns the given pool, or an error explaining why it is unavailable.
+fn available_pool<T>(pool: Option<T>, pools_released: bool) -> Result<T, ReplayError> {
+    match pool {
+        Some(pool) => Ok(pool),
+        None if pools_released => Err(ReplayError::PoolReleased),
+        None => Err(ReplayError::NoConsensusPool),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_test_utilities::types::{
+        batch::{BatchBuilder, PayloadBuilder},
+        ids::canister_test_id,
+        messages::SignedIngressBuilder,
+    };
+    use ic_types::crypto::CryptoHash;
+    use ic_types::state_sync::ChunkInfo;
+
+    // Message routing recording all delivered batches.
+    #[derive(Default)]
+    struct RecordingMessageRouting {
+        batches: Mutex<Vec<Batch>>,
+    }
+
+    impl MessageRouting for RecordingMessageRouting {
+        fn deliver_batch(&self, batch: Batch) -> Result<(), MessageRoutingError> {
+            self.batches.lock().unwrap().push(batch);
+            Ok(())
+        }
+
+        fn expected_batch_height(&self) -> Height {
+            Height::from(self.batches.lock().unwrap().len() as u64)
+        }
+    }
+
+    // Returns a manifest with one chunk per given hash byte.
+    fn manifest_with_chunks(hashes: &[u8]) -> Manifest {
+        Manifest {
+            version: 0,
+            file_table: vec![],
+            chunk_table: hashes
+                .iter()
+                .map(|hash| ChunkInfo {
+                    file_index: 0,
+                    size_bytes: 1,
+                    offset: 0,
+                    hash: [*hash; 32],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn audit_log_entries_contain_the_hash_of_the_previous_entry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("audit.log");
+        let mut audit_log = AuditLog {
+            writer: Box::new(std::fs::File::create(&path).unwrap()),
+            last_entry_hash: [0; 32],
+        };
+
+        audit_log.append("first".to_string());
+        audit_log.append("second".to_string());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], format!("{} first", hex::encode([0; 32])));
+        assert_eq!(
+            lines[1],
+            format!(
+                "{} second",
+                hex::encode(ic_crypto_sha::Sha256::hash(lines[0].as_bytes()))
+            )
+        );
+        assert_eq!(
+            audit_log.last_entry_hash,
+            ic_crypto_sha::Sha256::hash(lines[1].as_bytes())
+        );
+    }
+
+    #[test]
+    fn differing_manifest_chunks_compares_chunk_hashes() {
+        let manifest = manifest_with_chunks(&[1, 2, 3]);
+
+        assert!(differing_manifest_chunks(&manifest, &manifest).is_empty());
+        assert_eq!(
+            differing_manifest_chunks(&manifest, &manifest_with_chunks(&[1, 5, 3])),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn differing_manifest_chunks_includes_chunks_present_in_one_manifest() {
+        let manifest = manifest_with_chunks(&[1, 2, 3]);
+
+        assert_eq!(
+            differing_manifest_chunks(&manifest, &manifest_with_chunks(&[1, 2])),
+            vec![2]
+        );
+        assert_eq!(
+            differing_manifest_chunks(&manifest_with_chunks(&[4]), &manifest),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn extra_messages_summary_groups_rejects_by_code() {
+        let mut summary = ExtraMessagesSummary {
+            succeeded: 2,
+            ..Default::default()
+        };
+        assert!(summary.all_succeeded());
+
+        summary.add_reject(RejectCode::CanisterReject, "out of cycles".to_string());
+        summary.add_reject(RejectCode::CanisterReject, "out of cycles".to_string());
+        summary.add_reject(RejectCode::CanisterError, "trapped".to_string());
+
+        assert!(!summary.all_succeeded());
+        assert_eq!(
+            summary.rejected.get(&RejectCode::CanisterReject),
+            Some(&(2, vec!["out of cycles".to_string()].into_iter().collect()))
+        );
+        assert_eq!(
+            summary.rejected.get(&RejectCode::CanisterError),
+            Some(&(1, vec!["trapped".to_string()].into_iter().collect()))
+        );
+        assert!(summary
+            .to_string()
+            .starts_with("Extra messages: 2 succeeded, 3 rejected, 0 unfinished\n"));
+    }
+
+    #[test]
+    fn allowlist_message_routing_drops_messages_to_other_canisters() {
+        let recording = RecordingMessageRouting::default();
+        let allowlist: HashSet<_> = vec![canister_test_id(1)].into_iter().collect();
+        let message_routing = AllowlistMessageRouting {
+            message_routing: &recording,
+            allowlist: &allowlist,
+        };
+        let allowed = SignedIngressBuilder::new()
+            .canister_id(canister_test_id(1))
+            .nonce(1)
+            .build();
+        let dropped = SignedIngressBuilder::new()
+            .canister_id(canister_test_id(2))
+            .nonce(2)
+            .build();
+        let batch = BatchBuilder::new()
+            .payload(
+                PayloadBuilder::new()
+                    .ingress(IngressPayload::from(vec![allowed.clone(), dropped]))
+                    .build(),
+            )
+            .build();
+
+        message_routing.deliver_batch(batch).unwrap();
+
+        let batches = recording.batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            Vec::<SignedIngress>::try_from(batches[0].payload.ingress.clone()).unwrap(),
+            vec![allowed]
+        );
+    }
+
+    #[test]
+    fn no_op_message_routing_only_advances_the_expected_batch_height() {
+        let message_routing = NoOpMessageRouting {
+            expected_batch_height: AtomicU64::new(1),
+        };
+        let batch = BatchBuilder::new().batch_number(Height::from(1)).build();
+
+        assert_eq!(message_routing.deliver_batch(batch.clone()), Ok(()));
+        assert_eq!(message_routing.expected_batch_height(), Height::from(2));
+        assert_eq!(
+            message_routing.deliver_batch(batch),
+            Err(MessageRoutingError::Ignored {
+                expected_height: Height::from(2),
+                actual_height: Height::from(1),
+            })
+        );
+        assert_eq!(message_routing.expected_batch_height(), Height::from(2));
+    }
+
+    #[test]
+    fn checkpointless_message_routing_never_requests_a_checkpoint() {
+        let recording = RecordingMessageRouting::default();
+        let message_routing = CheckpointlessMessageRouting {
+            message_routing: &recording,
+            checkpoint_height: None,
+        };
+        let mut batch = BatchBuilder::new().build();
+        batch.requires_full_state_hash = true;
+
+        message_routing.deliver_batch(batch).unwrap();
+
+        let batches = recording.batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(!batches[0].requires_full_state_hash);
+        assert_eq!(message_routing.expected_batch_height(), Height::from(1));
+    }
+
+    #[test]
+    fn verify_nth_checkpoints_compares_the_nearest_cup_below_every_nth_checkpoint() {
+        let hash = |byte| CryptoHashOfState::from(CryptoHash(vec![byte]));
+        let heights = |heights: &[u64]| {
+            heights
+                .iter()
+                .copied()
+                .map(Height::from)
+                .collect::<Vec<_>>()
+        };
+        let new_checkpoints = heights(&[10, 15, 20, 25, 30]);
+        let cup_heights: BTreeSet<_> = heights(&[10, 20, 30]).into_iter().collect();
+        let compared = RefCell::new(Vec::new());
+        // The checkpoint certified by the CUP at height 20 diverges.
+        let checkpoint_state_hash = |height: Height| {
+            compared.borrow_mut().push(height);
+            Ok(hash(if height == Height::from(20) { 1 } else { 0 }))
+        };
+
+        // Every 2nd checkpoint, i.e. the non-CUP heights 15 and 25, is verified
+        // against the CUPs at heights 10 and 20.
+        let result = verify_nth_checkpoints(
+            &new_checkpoints,
+            0,
+            2,
+            &cup_heights,
+            |_| Ok(hash(0)),
+            checkpoint_state_hash,
+        );
+
+        assert!(matches!(
+            result,
+            Err(ReplayError::StateDivergence { height, .. }) if height == Height::from(20)
+        ));
+        assert_eq!(*compared.borrow(), heights(&[10, 20]));
+    }
+
+    #[test]
+    fn verify_nth_checkpoints_counts_the_checkpoints_seen_before() {
+        let hash = CryptoHashOfState::from(CryptoHash(vec![0]));
+        let cup_heights: BTreeSet<_> = vec![Height::from(10)].into_iter().collect();
+        let compared = RefCell::new(Vec::new());
+
+        // One checkpoint was seen before, so the single new one is the 2nd.
+        verify_nth_checkpoints(
+            &[Height::from(12)],
+            1,
+            2,
+            &cup_heights,
+            |_| Ok(hash.clone()),
+            |height| {
+                compared.borrow_mut().push(height);
+                Ok(hash.clone())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(*compared.borrow(), vec![Height::from(10)]);
+    }
+
+    #[test]
+    fn pipelined_restore_only_skips_the_synchronization_for_delivered_states() {
+        use backup::ExitPoint;
+        let h = Height::from;
+        let skips = |pipelined, exit_point, target_time_reached, target_height| {
+            pipelined_certified_height(
+                pipelined,
+                &exit_point,
+                h(20),
+                target_time_reached,
+                target_height,
+            )
+        };
+
+        assert_eq!(
+            skips(true, ExitPoint::StateBehind(h(15)), false, None),
+            Some(h(15))
+        );
+        assert_eq!(
+            skips(true, ExitPoint::StateBehind(h(20)), false, Some(h(30))),
+            Some(h(20))
+        );
+        // The sequential mode always synchronizes.
+        assert_eq!(
+            skips(false, ExitPoint::StateBehind(h(15)), false, None),
+            None
+        );
+        // The next artifacts must not be deserialized before the batch of the
+        // certified height is delivered, so that the delivery order is kept.
+        assert_eq!(
+            skips(true, ExitPoint::StateBehind(h(21)), false, None),
+            None
+        );
+        // CUPs and registry updates remain synchronization points.
+        assert_eq!(
+            skips(true, ExitPoint::CUPHeightWasFinalized(h(20)), false, None),
+            None
+        );
+        assert_eq!(
+            skips(
+                true,
+                ExitPoint::NewerRegistryVersion(RegistryVersion::from(2)),
+                false,
+                None
+            ),
+            None
+        );
+        assert_eq!(skips(true, ExitPoint::Done, false, None), None);
+        // So is reaching the target height or time.
+        assert_eq!(
+            skips(true, ExitPoint::StateBehind(h(15)), false, Some(h(20))),
+            None
+        );
+        assert_eq!(skips(true, ExitPoint::StateBehind(h(15)), true, None), None);
+    }
+
+    #[test]
+    fn resume_point_falls_back_to_the_pool_cup_for_an_empty_state_manager() {
+        let h = Height::from;
+        assert_eq!(
+            resume_point(&[h(100), h(200)], &[], h(0), h(0), h(100)),
+            ResumePoint {
+                cup_height: h(100),
+                first_batch_height: h(101),
             }
-            Err(err) => {
-                panic!("State computation failed: {:?}", err)
+        );
+    }
+
+    #[test]
+    fn resume_point_uses_the_highest_cup_with_a_checkpoint_below_the_latest_state() {
+        let h = Height::from;
+        let cup_heights = [h(100), h(200), h(300), h(400)];
+        // There is no checkpoint for the CUP at height 300 anymore, and the
+        // CUP at height 400 is above the latest state.
+        let checkpoint_heights = [h(100), h(200)];
+
+        let resume_point = resume_point(&cup_heights, &checkpoint_heights, h(350), h(350), h(100));
+
+        assert_eq!(resume_point.cup_height, h(200));
+    }
+
+    #[test]
+    fn resume_point_skips_already_executed_batches() {
+        let h = Height::from;
+        let checkpoint_heights = [h(100), h(200)];
+
+        let resume_point = resume_point(
+            &[h(100), h(200)],
+            &checkpoint_heights,
+            h(250),
+            h(250),
+            h(100),
+        );
+
+        assert_eq!(
+            resume_point,
+            ResumePoint {
+                cup_height: h(200),
+                first_batch_height: h(251),
             }
+        );
+    }
+
+    #[test]
+    fn peak_state_estimate_counts_three_copies_of_the_state() {
+        assert_eq!(estimate_peak_state_bytes(0, 0), 0);
+        assert_eq!(estimate_peak_state_bytes(100, 0), 300);
+        assert_eq!(estimate_peak_state_bytes(100, 10), 320);
+    }
+
+    #[test]
+    fn latest_record_version_is_the_highest_record_version() {
+        let record = |version| RegistryTransportRecord {
+            key: "key".to_string(),
+            version: RegistryVersion::from(version),
+            value: None,
+        };
+
+        assert_eq!(
+            latest_record_version(RegistryVersion::from(3), &[]),
+            RegistryVersion::from(3)
+        );
+        assert_eq!(
+            latest_record_version(RegistryVersion::from(3), &[record(5), record(4), record(5)]),
+            RegistryVersion::from(5)
+        );
+    }
+
+    #[test]
+    fn final_state_params_require_the_last_restored_height() {
+        assert!(matches!(
+            final_state_params(Height::from(7), Height::from(7), "ab".to_string()),
+            Ok(hash) if hash == "ab"
+        ));
+        assert!(matches!(
+            final_state_params(Height::from(7), Height::from(5), "ab".to_string()),
+            Err(ReplayError::StateHeightMismatch { expected, actual })
+                if expected == Height::from(7) && actual == Height::from(5)
+        ));
+    }
+
+    #[test]
+    fn checkpointless_message_routing_requests_a_checkpoint_only_at_the_checkpoint_height() {
+        let recording = RecordingMessageRouting::default();
+        let message_routing = CheckpointlessMessageRouting {
+            message_routing: &recording,
+            checkpoint_height: Some(Height::from(3)),
+        };
+
+        for height in 1..=4 {
+            let mut batch = BatchBuilder::new()
+                .batch_number(Height::from(height))
+                .build();
+            batch.requires_full_state_hash = height == 2;
+            message_routing.deliver_batch(batch).unwrap();
         }
-        std::thread::sleep(WAIT_DURATION);
+
+        let checkpoint_heights: Vec<_> = recording
+            .batches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|batch| batch.requires_full_state_hash)
+            .map(|batch| batch.batch_number)
+            .collect();
+        assert_eq!(checkpoint_heights, vec![Height::from(3)]);
     }
 }
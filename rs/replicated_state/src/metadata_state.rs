@@ -31,7 +31,7 @@ use ic_types::{
     CountBytes, CryptoHashOfPartialState, NodeId, NumBytes, PrincipalId, SubnetId,
 };
 use serde::{Deserialize, Serialize};
-use std::ops::Bound::{Included, Unbounded};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::{
     collections::{BTreeMap, BTreeSet, VecDeque},
     convert::{From, TryFrom, TryInto},
@@ -1128,6 +1128,22 @@ impl IngressHistoryState {
             .map(|(id, status)| (id, status.as_ref()))
     }
 
+    /// Returns an iterator over the response statuses following the given
+    /// message id, sorted lexicographically by message id. Starts from the
+    /// first status if no message id is given.
+    pub fn statuses_after(
+        &self,
+        message_id: Option<&MessageId>,
+    ) -> impl Iterator<Item = (&MessageId, &IngressStatus)> {
+        let lower_bound = match message_id {
+            Some(message_id) => Excluded(message_id),
+            None => Unbounded,
+        };
+        self.statuses
+            .range::<MessageId, _>((lower_bound, Unbounded))
+            .map(|(id, status)| (id, status.as_ref()))
+    }
+
     /// Returns an iterator over pruning times statuses, sorted
     /// lexicographically by time.
     pub fn pruning_times(&self) -> impl Iterator<Item = (&Time, &BTreeSet<MessageId>)> {
@@ -116,6 +116,40 @@ fn entries_sorted_lexicographically() {
     assert_eq!(actual, expected);
 }
 
+#[test]
+fn statuses_after_returns_the_statuses_following_the_message_id() {
+    let mut ingress_history = IngressHistoryState::new();
+    let time = mock_time();
+
+    for i in 0..10u64 {
+        ingress_history.insert(
+            message_test_id(i),
+            test_status_done(i),
+            time,
+            NumBytes::from(u64::MAX),
+        );
+    }
+    let mut ids: Vec<_> = (0..10u64).map(message_test_id).collect();
+    ids.sort();
+    let statuses_after = |message_id: Option<&MessageId>| {
+        ingress_history
+            .statuses_after(message_id)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // Without a message id, all statuses are returned.
+    assert_eq!(statuses_after(None), ids);
+    // The given message id itself is excluded.
+    assert_eq!(statuses_after(Some(&ids[4])), ids[5..].to_vec());
+    assert!(statuses_after(Some(&ids[9])).is_empty());
+    // An unknown message id continues with the next known one.
+    let unknown = message_test_id(10);
+    assert!(!ids.contains(&unknown));
+    let expected: Vec<_> = ids.iter().filter(|id| **id > unknown).cloned().collect();
+    assert_eq!(statuses_after(Some(&unknown)), expected);
+}
+
 #[test]
 fn streams_stats() {
     // Two local canisters, `local_a` and `local_b`.
@@ -1363,6 +1363,11 @@ impl StateManagerImpl {
         &self.state_layout
     }
 
+    /// Returns the number of states currently held in memory.
+    pub fn resident_state_count(&self) -> usize {
+        self.states.read().snapshots.len()
+    }
+
     /// Returns requested state as a Chunkable artifact for StateSync.
     pub fn create_chunkable_state(
         &self,
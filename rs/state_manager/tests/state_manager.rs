@@ -767,6 +767,27 @@ fn can_remove_checkpoints() {
     });
 }
 
+#[test]
+fn resident_state_count_follows_commits_and_removals() {
+    state_manager_test(|metrics, state_manager| {
+        let initial_count = state_manager.resident_state_count();
+        for i in 1..=3 {
+            let (_height, state) = state_manager.take_tip();
+            state_manager.commit_and_certify(state, height(i), CertificationScope::Metadata);
+        }
+
+        assert_eq!(state_manager.resident_state_count(), initial_count + 3);
+        assert_eq!(
+            fetch_int_gauge(metrics, "state_manager_resident_state_count"),
+            Some(state_manager.resident_state_count() as u64)
+        );
+
+        state_manager.remove_inmemory_states_below(height(3));
+
+        assert!(state_manager.resident_state_count() < initial_count + 3);
+    });
+}
+
 #[test]
 fn cannot_remove_height_zero() {
     state_manager_test(|_metrics, state_manager| {
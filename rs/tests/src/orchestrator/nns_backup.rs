@@ -130,9 +130,13 @@ pub fn test(env: TestEnv) {
 
     let upgrade_height = match backup.replay(&replica_version) {
         Err(ReplayError::UpgradeDetected((h, _))) => h,
-        Err(ReplayError::StateDivergence(height)) => panic!(
-            "State computation diverged pre-upgrade at height {}",
-            height
+        Err(ReplayError::StateDivergence {
+            height,
+            expected,
+            actual,
+        }) => panic!(
+            "State computation diverged pre-upgrade at height {}: expected {:?}, actual {:?}",
+            height, expected, actual
         ),
         _ => panic!("No upgrade was detected"),
     };
@@ -59,6 +59,7 @@ impl Backup {
                 backup_spool_path: PathBuf::from(&self.spool_path()),
                 replica_version: replica_version.to_string(),
                 start_height,
+                validate_replica_version: false,
             })),
         };
         self.print_contents_of_dir(&self.local_store_path());